@@ -1,8 +1,7 @@
 use std::boxed::Box;
 use std::fmt;
-use std::fmt::Write;
 
-use crate::lexer::{Symbol, TokenKind};
+use crate::lexer::{Pos, Symbol, TokenKind};
 
 /*
 let x = 1 + 1
@@ -23,20 +22,37 @@ let x = 1 + 1
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum Expr {
-    Int(u64),
+    Int(i64),
     Str(String),
+    /// A string made of literal `Str` parts and the values of `{expr}` parts
+    Interpolated(Vec<Expr>),
+    Bool(bool),
+    Array(Vec<Expr>),
     Variable(String),
     Unary(Box<UnaryExpr>),
     Binary(Box<BinaryExpr>),
     Return(Box<Expr>),
     VariableDecl(Box<VariableDecl>),
-    ConstDecl(Box<ConstDecl>),
     If(Box<If>),
     FuncDef(Box<FuncDef>),
     FuncCall(Box<FuncCall>),
     While(Box<While>),
     Loop(Box<Loop>),
     For(Box<For>),
+    Match(Box<Match>),
+    Try(Box<Try>),
+    /// A command line written between backticks
+    Command(Box<Command>),
+    /// The declarative command line flags of the script
+    Flags(Vec<Flag>),
+    /// `import "path";`, which makes the functions of another file callable
+    /// as `module::name()`
+    Import(String),
+    Use(Box<Use>),
+    Extern(Box<Extern>),
+    /// `spawn { ... }` or `spawn f(x)`, which runs the block or call alongside
+    /// the rest of the script, and whose value is a handle for `wait()`
+    Spawn(Box<Expr>),
     Continue,
     Break,
     Block(Vec<Expr>),
@@ -49,11 +65,37 @@ pub struct UnaryExpr {
     pub child: Expr,
 }
 
+/// Where a node is written, for errors and lints. Positions aren't part of
+/// the program, so any two compare equal, which lets the formatter check that
+/// formatting didn't change the program.
+#[derive(Debug, Copy, Clone)]
+pub struct SourcePos(pub Pos);
+
+impl PartialEq for SourcePos {
+    fn eq(&self, _: &SourcePos) -> bool {
+        true
+    }
+}
+
+impl Eq for SourcePos {}
+
+impl std::hash::Hash for SourcePos {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct BinaryExpr {
     pub op: BinaryOpKind,
     pub left: Expr,
     pub right: Expr,
+    /// Where the operator is written
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
@@ -61,6 +103,8 @@ pub struct FuncDef {
     pub name: String,
     pub params: Vec<String>,
     pub body: Expr,
+    /// Where the function's name is written
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
@@ -74,6 +118,7 @@ pub struct If {
     pub cond: Expr,
     pub then: Expr,
     pub else_: Expr,
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
@@ -81,12 +126,15 @@ pub struct For {
     pub item: String,
     pub container: Expr,
     pub body: Expr,
+    /// Where the item's name is written
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct While {
     pub cond: Expr,
     pub body: Expr,
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
@@ -95,16 +143,162 @@ pub struct Loop {
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
-pub struct VariableDecl {
-    pub name: String,
+pub struct Match {
     pub value: Expr,
-    pub is_numeric: bool,
+    pub arms: Vec<MatchArm>,
+    /// The body of the `_` arm, if there is one
+    pub default: Option<Expr>,
+    pub ignore_case: bool,
+    pub pos: SourcePos,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Expr>,
+    pub body: Expr,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Try {
+    pub body: Expr,
+    /// The variable that holds the error message, and the block that
+    /// handles the error
+    pub catch: Option<(String, Expr)>,
+    pub finally: Option<Expr>,
+    pub pos: SourcePos,
+}
+
+/// `use module::{a, b};`, which makes functions of an imported module
+/// callable without the module's name
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Use {
+    pub module: String,
+    pub names: Vec<String>,
+}
+
+/// `extern fn build(target) from "legacy/build.bat";`, a function that is
+/// a batch file, or a label in one
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Extern {
+    pub name: String,
+    pub params: Vec<String>,
+    pub path: String,
+    /// The label called in the batch file, which the file has to dispatch
+    /// to itself
+    pub label: Option<String>,
+    /// The variable the batch file leaves its result in, which is the value
+    /// of a call. Without one, a call's value is its exit code
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Command {
+    /// The commands of a pipeline, each reading the output of the one before
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Stage {
+    /// The program and its arguments, each a `Str` or `Interpolated`
+    pub words: Vec<Expr>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Redirect {
+    pub kind: RedirectKind,
+    /// The file redirected to or from, which only `ErrToOut` doesn't have
+    pub target: Option<Expr>,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub enum RedirectKind {
+    /// `< file`
+    In,
+    /// `> file`
+    Out,
+    /// `>> file`
+    Append,
+    /// `2> file`
+    Err,
+    /// `2>> file`
+    ErrAppend,
+    /// `2>&1`
+    ErrToOut,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Flag {
+    pub name: String,
+    pub kind: FlagKind,
+    pub default: Option<Expr>,
+    pub pos: SourcePos,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub enum FlagKind {
+    /// Takes no value, and is `1` when given and `0` otherwise
+    Bool,
+    Str,
+    Path,
+    Num,
+}
+
+impl Expr {
+    /// The expressions directly inside this one
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Int(_) | Expr::Str(_) | Expr::Bool(_) | Expr::Variable(_) => Vec::new(),
+            Expr::Continue | Expr::Break => Vec::new(),
+            Expr::Import(_) | Expr::Use(_) | Expr::Extern(_) => Vec::new(),
+            Expr::Unary(u) => vec![&u.child],
+            Expr::Binary(b) => vec![&b.left, &b.right],
+            Expr::Return(e) | Expr::Paren(e) | Expr::Spawn(e) => vec![e],
+            Expr::VariableDecl(v) => vec![&v.value],
+            Expr::If(i) => vec![&i.cond, &i.then, &i.else_],
+            Expr::FuncDef(f) => vec![&f.body],
+            Expr::FuncCall(c) => c.params.iter().collect(),
+            Expr::Array(items) | Expr::Interpolated(items) => items.iter().collect(),
+            Expr::While(w) => vec![&w.cond, &w.body],
+            Expr::Loop(l) => vec![&l.body],
+            Expr::For(f) => vec![&f.container, &f.body],
+            Expr::Match(m) => {
+                let mut children = vec![&m.value];
+                for arm in &m.arms {
+                    children.extend(arm.patterns.iter());
+                    children.push(&arm.body);
+                }
+                children.extend(m.default.iter());
+                children
+            }
+            Expr::Try(t) => {
+                let mut children = vec![&t.body];
+                children.extend(t.catch.iter().map(|(_, body)| body));
+                children.extend(t.finally.iter());
+                children
+            }
+            Expr::Command(c) => c
+                .stages
+                .iter()
+                .flat_map(|s| {
+                    let targets = s.redirects.iter().filter_map(|r| r.target.as_ref());
+                    s.words.iter().chain(targets)
+                })
+                .collect(),
+            Expr::Flags(flags) => flags.iter().filter_map(|f| f.default.as_ref()).collect(),
+            Expr::Block(stmts) => stmts.iter().collect(),
+        }
+    }
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
-pub struct ConstDecl {
+pub struct VariableDecl {
     pub name: String,
     pub value: Expr,
+    pub is_numeric: bool,
+    pub is_exported: bool,
+    /// Where the variable's name is written
+    pub pos: SourcePos,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
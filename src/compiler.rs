@@ -1,16 +1,152 @@
-use std::fs;
-use std::io::prelude::*;
-use std::io::{self, BufWriter, Write};
+use std::fmt;
+use std::io::{self, Write};
 use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use crate::ast::*;
-static SCOPE: AtomicUsize = AtomicUsize::new(0);
+use crate::lexer::Pos;
+use crate::mangle::mangle;
+use crate::module::{self, Module};
+
+/// Prepended to every variable that isn't exported, so that ski variables
+/// can't clobber the environment, e.g. `let path` overwriting `%PATH%`
+const VAR_PREFIX: &str = "ski_";
+
+/// Letters available for cmd `FOR` variables. Letters that are also `%~`
+/// modifiers, like `f` in `%%~fi`, are left out to keep expansions unambiguous
+const FOR_VARS: &[u8] = b"bceghijklmoqruvwyBCEGHIJKLMOQRUVWY";
+
+/// The script's arguments, stored like an array, that `args()` returns
+const ARGS: &str = "__ski_args";
+/// The number of items in `ARGS`, that `args_count()` returns
+const ARGS_COUNT: &str = "__ski_args_count";
+/// The exit code of the last command or function, that `last_status()`
+/// returns. `%ERRORLEVEL%` itself doesn't last, as `SET` resets it in a
+/// `.cmd` file.
+const STATUS: &str = "__ski_status";
+/// Set to the script's exit code by `exit()`, telling every subroutine on the
+/// way out to return at once
+const EXIT: &str = "__ski_exit";
+/// The message of the error being raised. Subroutines pass an error to their
+/// caller by returning while it is set, with the error code in `STATUS`
+const ERROR: &str = "__ski_error";
+/// Prints an error that no `try` caught and ends the script
+const UNCAUGHT: &str = "__ski_uncaught";
+/// Ends a script that sets environment variables, passing them back to its
+/// caller
+const END: &str = "__ski_end";
+/// Runs a spawned subroutine in the process that `START` launched for it,
+/// then marks its handle as finished by writing the exit code to it
+const SPAWNED: &str = "__ski_spawned";
+
+/// How `break` and `continue` leave a loop
+#[derive(Debug)]
+enum Jump {
+    /// Loops made of labels, which are left with `GOTO`
+    Label { next: String, end: String },
+    /// Loops whose body is a subroutine called from a cmd `FOR`. The body can
+    /// only return, so `break` also raises a flag that makes the `FOR` skip
+    /// its remaining items
+    Subroutine { break_flag: String },
+}
+
+/// A `try` statement enclosing the code being compiled
+#[derive(Debug)]
+struct Handler {
+    /// The label that errors jump to, or `None` while compiling the `catch`
+    /// block of a `try` without a `finally` block, which lets errors through
+    label: Option<String>,
+    has_finally: bool,
+    /// The number of loops enclosing the `try`
+    loops: usize,
+}
+
+/// How the items a cmd `FOR` loops over are written
+#[derive(Debug, Copy, Clone)]
+enum Items {
+    /// As they are
+    Plain,
+    /// Lines numbered by `FINDSTR /N`, so that `FOR /F` doesn't skip empty
+    /// lines or lines starting with `;`
+    Numbered,
+    /// Array items, quoted with `quote_item`
+    Array,
+}
+
 pub struct Compiler<W: Write> {
     buf: W,
-    stack: Vec<usize>,
-    for_var: HashSet<String>
+    /// How to leave each of the loops enclosing the code being compiled
+    stack: Vec<Jump>,
+    /// Number of labels handed out so far, used to keep generated labels unique
+    labels: usize,
+    /// Number of enclosing loops that lowered to a cmd `FOR`, each of which
+    /// holds on to one of the `FOR_VARS`
+    for_depth: usize,
+    /// Variables written to cmd under their own name: exported variables and
+    /// compiler temporaries
+    exported: HashSet<String>,
+    /// Whether the text being written is inside double quotes, where cmd's
+    /// special characters don't need escaping
+    in_quotes: bool,
+    /// Whether the text being written is an item of an array, where quotes
+    /// are doubled like `quote_item` does
+    in_array: bool,
+    /// Whether the text being written is the argument of an `ECHO`
+    in_echo: bool,
+    /// Whether the line being written runs with delayed expansion, so
+    /// variables are read with `!name!` after cmd has parsed the line, and
+    /// their values can't run as cmd syntax
+    delayed: bool,
+    /// The redirection that starts every line of an `ECHO` to a file
+    echo_redirect: Option<String>,
+    /// Variables that were assigned an array
+    arrays: HashSet<String>,
+    /// Maps every generated cmd name back to the ski identifier it came from
+    names: BTreeMap<String, String>,
+    /// Whether the expression being written is part of a constant expression
+    /// that was already checked for overflow
+    in_const: bool,
+    /// Whether to stop the script as soon as a command fails
+    fail_fast: bool,
+    /// Whether the program can exit from inside a subroutine, so every `CALL`
+    /// has to check for it
+    exits: bool,
+    /// Whether `STATUS` has to be kept up to date
+    tracks_status: bool,
+    /// Whether the program can raise errors, so every `CALL` has to check
+    /// for one
+    raises: bool,
+    /// The `try` statements enclosing the code being compiled, innermost
+    /// last, up to the start of the current subroutine
+    handlers: Vec<Handler>,
+    /// The number of `try` blocks enclosing the code being compiled, in which
+    /// a failed command raises an error
+    trying: usize,
+    /// Whether the code being compiled is a subroutine, which passes errors
+    /// on to its caller
+    in_subroutine: bool,
+    /// Whether the program sets environment variables, so the script has to
+    /// end at `END`
+    sets_env: bool,
+    /// The environment variables the program sets
+    environment: BTreeSet<String>,
+    /// Whether the program spawns subroutines, which are started by running
+    /// the file again
+    spawns: bool,
+    /// Whether each imported module is compiled to a file of its own, rather
+    /// than after the script
+    split: bool,
+    /// The imported module being compiled, or `None` for the script
+    module: Option<String>,
+    /// The functions declared by each imported module
+    functions: HashMap<String, HashSet<String>>,
+    /// The modules imported by the file being compiled
+    imports: HashSet<String>,
+    /// The functions brought in by `use` in the file being compiled, and the
+    /// modules they are from
+    uses: HashMap<String, String>,
+    /// The batch files declared with `extern fn` in the file being compiled
+    externs: HashMap<String, Extern>,
 }
 
 impl<W: Write> Compiler<W> {
@@ -18,301 +154,2820 @@ impl<W: Write> Compiler<W> {
         Compiler {
             buf,
             stack: Vec::new(),
-            for_var: HashSet::new()
+            labels: 0,
+            for_depth: 0,
+            exported: HashSet::new(),
+            in_quotes: false,
+            in_array: false,
+            in_echo: false,
+            delayed: false,
+            echo_redirect: None,
+            arrays: HashSet::new(),
+            names: BTreeMap::new(),
+            in_const: false,
+            fail_fast: false,
+            exits: false,
+            tracks_status: false,
+            raises: false,
+            handlers: Vec::new(),
+            trying: 0,
+            in_subroutine: false,
+            sets_env: false,
+            environment: BTreeSet::new(),
+            spawns: false,
+            split: false,
+            module: None,
+            functions: HashMap::new(),
+            imports: HashSet::new(),
+            uses: HashMap::new(),
+            externs: HashMap::new(),
         }
     }
 
-    pub fn compile(&mut self, ast: Expr) -> io::Result<()> {
+    /// In fail fast mode, a command that exits with a non-zero code that
+    /// isn't stored raises an error, which ends the script with that code
+    /// unless it is caught, like `set -e` in sh
+    pub fn fail_fast(mut self, fail_fast: bool) -> Compiler<W> {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Compile imported modules to files of their own, see `compile_module`,
+    /// instead of writing their functions into the script
+    pub fn split(mut self, split: bool) -> Compiler<W> {
+        self.split = split;
+        self
+    }
+
+    /// Compiles the script, which is the last of `modules`. The functions of
+    /// the modules it imports are written after it, unless modules are split
+    pub fn compile(&mut self, modules: &[Module]) -> io::Result<()> {
+        let (script, imported) = match modules.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
         // fs::remove_file(&path)?;
         // let mut stream = BufWriter::new(fs::File::create(path)?);
-        self.buf.write(b"@echo off\n")?;
+        self.write_header()?;
+        // ski variables are local to the script, see `END`
+        self.buf.write_all(b"SETLOCAL\n")?;
+
+        self.survey(modules)?;
+        if self.spawns {
+            // a spawned process runs the script again, see `compile_spawn`
+            writeln!(self.buf, "IF \"%~1\"==\":{}\" (CALL %* & EXIT /B)", SPAWNED)?;
+        }
+        find_externs(&script.ast, &mut self.externs);
+        self.compile_args(&script.ast)?;
+        self.compile_expr(script.ast.clone())?;
+        let end = if self.sets_env { END } else { "EOF" };
+        let inlines = !self.split && !imported.is_empty();
+        if self.raises || self.spawns || inlines {
+            writeln!(self.buf, "GOTO :{}", end)?;
+        }
+        if !self.split {
+            for module in imported {
+                self.compile_functions(module)?;
+            }
+        }
+        if self.spawns {
+            self.write_spawned()?;
+        }
+        if self.raises {
+            writeln!(self.buf, ":{}", UNCAUGHT)?;
+            writeln!(self.buf, "ECHO(%{}% 1>&2", ERROR)?;
+            if self.sets_env {
+                writeln!(self.buf, "SET \"{}=%{}%\"", EXIT, STATUS)?;
+            } else {
+                writeln!(self.buf, "EXIT /B %{}%", STATUS)?;
+            }
+        }
+        if self.sets_env {
+            // `ENDLOCAL` throws away every variable set since `SETLOCAL`,
+            // but the rest of its line was expanded before it ran
+            writeln!(self.buf, ":{}", END)?;
+            self.buf.write_all(b"ENDLOCAL")?;
+            for name in &self.environment {
+                write!(self.buf, " & SET \"{0}=%{0}%\"", name)?;
+            }
+            writeln!(self.buf, " & EXIT /B %{}%", EXIT)?;
+        }
+        self.write_name_map()
+    }
+
+    /// Compiles `module`, one of the imported `modules`, to a file of its
+    /// own. Other files call its functions with `CALL module.cmd :function`,
+    /// and the file calls the label it is given, so the function runs as a
+    /// subroutine of the file with the remaining arguments
+    pub fn compile_module(&mut self, modules: &[Module], module: &Module) -> io::Result<()> {
+        self.write_header()?;
+        self.buf.write_all(b"CALL %*\nEXIT /B\n")?;
+        self.survey(modules)?;
+        self.compile_functions(module)?;
+        if self.spawns {
+            self.write_spawned()?;
+        }
+        self.write_name_map()
+    }
+
+    /// The entry point of spawned processes, which are started with the
+    /// label to run and the file to write its exit code to
+    fn write_spawned(&mut self) -> io::Result<()> {
+        writeln!(self.buf, ":{}", SPAWNED)?;
+        self.buf.write_all(b"CALL :%~1\n")?;
+        if self.raises {
+            writeln!(self.buf, "IF DEFINED {0} ECHO(%{0}% 1>&2", ERROR)?;
+        }
+        self.buf.write_all(b"IF \"%~2\"==\"\" EXIT /B\n")?;
+        // the code is written to a file of its own and moved into place, so
+        // `wait()` never reads a half written file
+        self.buf.write_all(b">\"%~2.tmp\" ECHO(%ERRORLEVEL%\n")?;
+        self.buf.write_all(b"MOVE /Y \"%~2.tmp\" \"%~2\" >NUL\n")?;
+        self.buf.write_all(b"EXIT /B\n")?;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.buf.write_all(b"@echo off\n")?;
         self.buf
-            .write(b"REM AUTO-GENERATED FILE. DO NOT MODIFY.\n")?;
+            .write_all(b"REM AUTO-GENERATED FILE. DO NOT MODIFY.\n")?;
         self.buf
-            .write(b"REM This file was automatically generated by the ski compiler.\n")?;
+            .write_all(b"REM This file was automatically generated by the ski compiler.\n")?;
+        Ok(())
+    }
 
-        self.compile_expr(ast)?;
+    fn write_name_map(&mut self) -> io::Result<()> {
+        if !self.names.is_empty() {
+            self.buf.write_all(b"\nREM Name map (cmd -> ski):\n")?;
+            for (cmd, ski) in &self.names {
+                writeln!(self.buf, "REM   {} -> {}", cmd, ski)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds out what the whole program does that every file of it has to be
+    /// ready for, like a function in one module exiting the script
+    fn survey(&mut self, modules: &[Module]) -> io::Result<()> {
+        let any = |f: &dyn Fn(&Expr) -> bool| modules.iter().any(|m| f(&m.ast));
+        self.exits = any(&|ast| calls(ast, "exit"));
+        self.tracks_status = any(&|ast| calls(ast, "last_status"));
+        self.raises = self.fail_fast
+            || any(&|ast| calls(ast, "throw"))
+            || any(&|ast| contains(ast, &|e| matches!(e, Expr::Try(_))));
+        self.sets_env = any(&|ast| calls(ast, "set_env"))
+            || any(&|ast| contains(ast, &|e| matches!(e, Expr::VariableDecl(v) if v.is_exported)));
+        self.spawns = any(&|ast| contains(ast, &|e| matches!(e, Expr::Spawn(_))));
+        for module in &modules[..modules.len() - 1] {
+            let functions = module.items().iter().filter_map(|item| match item {
+                Expr::FuncDef(f) => Some(f.name.clone()),
+                _ => None,
+            });
+            self.functions
+                .insert(module.name.clone(), functions.collect());
+            // a split module sets variables in the script's environment, which
+            // the script has to pass on
+            find_environment(&module.ast, &mut self.environment)?;
+        }
+        Ok(())
+    }
 
+    /// Writes the functions of an imported module, with labels prefixed by
+    /// the module's name
+    fn compile_functions(&mut self, module: &Module) -> io::Result<()> {
+        self.module = Some(module.name.clone());
+        self.imports.clear();
+        self.uses.clear();
+        self.externs.clear();
+        find_externs(&module.ast, &mut self.externs);
+        for item in module.items() {
+            self.compile_expr(item.clone())?;
+        }
+        self.module = None;
+        self.imports.clear();
+        self.uses.clear();
+        self.externs.clear();
         Ok(())
     }
 
     pub fn compile_func_call(&mut self, ast: Box<FuncCall>) -> io::Result<()> {
         match ast.func_name.as_str() {
-            "range" => self.compile_range(ast.params)?,
             "print" => self.compile_echo(ast.params)?,
-            _ => self.compile_func_calll(ast)?
+            "args" => self.read_var(ARGS)?,
+            name if is_command(name) => {
+                let mut call = *ast;
+                self.hoist_command_params(&mut call.params)?;
+                self.compile_command(call, None)?
+            }
+            "args_count" => self.read_var(ARGS_COUNT)?,
+            "last_status" => self.read_var(STATUS)?,
+            "exit" => self.compile_exit(ast.params)?,
+            "throw" => self.compile_throw(ast.params)?,
+            "env" | "set_env" => self.compile_env(*ast)?,
+            "wait" => self.compile_wait(ast.params, None)?,
+            "write_file" | "append_file" => self.compile_write_file(*ast)?,
+            "mkdir" | "remove" | "copy" | "move" => self.compile_file_op(*ast)?,
+            "exists" | "is_dir" => {
+                let is_dir = ast.func_name == "is_dir";
+                let mut params = ast.params;
+                match (params.pop(), params.is_empty()) {
+                    (Some(path), true) => self.compile_exists(path, is_dir)?,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("`{}` takes a path", ast.func_name),
+                        ))
+                    }
+                }
+            }
+            "return_status" => {
+                let mut params = ast.params;
+                match (params.pop(), params.is_empty()) {
+                    (Some(code), true) => {
+                        self.buf.write_all(b"EXIT /B ")?;
+                        self.compile_expr(code)?;
+                        self.buf.write_all(b"\n")?;
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "`return_status` takes an exit code",
+                        ))
+                    }
+                }
+            }
+            name if self.externs.contains_key(name) => {
+                let mut call = *ast;
+                for p in call.params.iter_mut() {
+                    self.hoist(p)?;
+                }
+                self.compile_extern_call(call, None)?
+            }
+            _ => self.compile_func_calll(ast, None)?
         }
         Ok(())
     }
 
-    pub fn compile_func_calll(&mut self, ast: Box<FuncCall>) -> io::Result<()> {
-        write!(self.buf, "CALL :{} ", ast.func_name)?;
+    /// Calls a ski function. With `result`, its name is passed first, for
+    /// the function's `return` to set
+    pub fn compile_func_calll(&mut self, mut ast: Box<FuncCall>, result: Option<String>) -> io::Result<()> {
+        for x in ast.params.iter_mut() {
+            self.hoist(x)?;
+        }
+        match self.resolve_function(&ast.func_name)? {
+            (label, Some(file)) => write!(self.buf, "CALL \"%~dp0{}\" :{} ", file, label)?,
+            (label, None) => write!(self.buf, "CALL :{} ", label)?,
+        }
+        if let Some(result) = result {
+            write!(self.buf, "{} ", result)?;
+        }
         self.compile_params(ast.params)?;
+        self.buf.write_all(b"\n")?;
+        self.compile_call_return()?;
         Ok(())
     }
 
-    pub fn compile_if(&mut self, ast: Box<If>) -> io::Result<()> {
-        let mut was_logical = false;
-        match &ast.cond {
-            Expr::Binary(i) => match i.op {
-              BinaryOpKind::BinaryAnd | BinaryOpKind::BinaryOr => {
-                  was_logical = true;
-                  self.compile_binary_and(i)?},
-              _ => {
-                  dbg!(&ast.cond);
-                self.buf.write(b"IF ")?;
-                self.compile_expr(ast.cond)?;
-                self.buf.write(b" (\n")?;
-            }
-            }
-            _ => {}    
-          }
-        self.compile_expr(ast.then)?;
-        self.buf.write(b")")?;
-        if was_logical {
-            self.buf.write(b")")?;
-        }
-        if ast.else_ != Expr::Block(Vec::new()) {
-            self.buf.write(b"ELSE (")?;
-            self.compile_expr(ast.else_)?;
-            self.buf.write(b"\n) ")?;
+    /// The label of the function `name`, and the file it is in when that
+    /// isn't the file being compiled. `util::f` and `f` after `use util::f`
+    /// are functions of the imported module `util`, and any other `f` is
+    /// one of the current file's
+    fn resolve_function(&self, name: &str) -> io::Result<(String, Option<String>)> {
+        let (module, name) = match name.split_once("::") {
+            Some((module, name)) => {
+                self.check_function(module, name)?;
+                (Some(module), name)
+            }
+            None => match self.uses.get(name) {
+                Some(module) => (Some(module.as_str()), name),
+                None => (self.module.as_deref(), name),
+            },
+        };
+        let file = match module {
+            Some(m) if self.split && self.module.as_deref() != Some(m) => {
+                Some(format!("{}.cmd", mangle(m)))
+            }
+            _ => None,
+        };
+        Ok((func_label(module, name), file))
+    }
+
+    /// Makes sure `module` is imported by the file being compiled and
+    /// declares the function `name`
+    fn check_function(&self, module: &str, name: &str) -> io::Result<()> {
+        let message = if !self.imports.contains(module) {
+            format!("module `{}` isn't imported", module)
+        } else if !self.functions.get(module).is_some_and(|f| f.contains(name)) {
+            format!("module `{}` has no function `{}`", module, name)
         } else {
-            self.buf.write(b"\n")?;
+            return Ok(());
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+    }
+
+    /// `use module::{a, b};` lets `a()` call `module::a()`
+    fn compile_use(&mut self, ast: Use) -> io::Result<()> {
+        for name in ast.names {
+            self.check_function(&ast.module, &name)?;
+            self.uses.insert(name, ast.module.clone());
         }
         Ok(())
     }
 
-    pub fn compile_range(&mut self, mut ast: Vec<Expr>) -> io::Result<()> {
-        self.buf.write(b"(")?;
-        let p = ast.pop();
-        for x in ast {
-            self.compile_expr(x)?;
-            self.buf.write(b",")?;
+    /// Follows a `CALL` of a ski subroutine. `ERRORLEVEL` is still the
+    /// subroutine's exit code here, so an exit can be passed on with a plain
+    /// `EXIT /B`
+    fn compile_call_return(&mut self) -> io::Result<()> {
+        if self.exits {
+            writeln!(self.buf, "IF DEFINED {} {}", EXIT, self.exit_with(""))?;
+        }
+        if self.raises {
+            writeln!(self.buf, "IF DEFINED {} {}", ERROR, self.raise())?;
+        }
+        if self.tracks_status {
+            writeln!(self.buf, "SET \"{}=%ERRORLEVEL%\"", STATUS)?;
         }
-        self.compile_expr(p.unwrap())?;
-        self.buf.write(b")")?;
         Ok(())
     }
 
-    pub fn compile_echo(&mut self, ast: Vec<Expr>) -> io::Result<()> {
-        self.buf.write(b"ECHO ")?;
-        self.compile_block(ast)?;
-        self.buf.write(b"\n")?;
+    /// `exit(code)` ends the script, even from inside a function or loop
+    fn compile_exit(&mut self, mut params: Vec<Expr>) -> io::Result<()> {
+        let code = match (params.pop(), params.is_empty()) {
+            (None, _) => Expr::Int(0),
+            (Some(code), true) => code,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`exit` takes at most one exit code",
+                ))
+            }
+        };
+        write!(self.buf, "SET \"{}=", EXIT)?;
+        self.compile_expr(code.clone())?;
+        self.buf.write_all(b"\"\n")?;
+        if self.sets_env && !self.in_subroutine {
+            writeln!(self.buf, "GOTO :{}", END)?;
+        } else {
+            self.buf.write_all(b"EXIT /B ")?;
+            self.compile_expr(code)?;
+            self.buf.write_all(b"\n")?;
+        }
         Ok(())
     }
 
-    pub fn compile_binary_and(&mut self, ast: &BinaryExpr) -> io::Result<()> {
-        self.buf.write(b"IF ")?;
-        self.compile_expr(ast.left.clone())?;
-        self.buf.write(b" (\n")?;
-        self.buf.write(b"IF ")?;
-        self.compile_expr(ast.right.clone())?;
-        self.buf.write(b")")?;
-        self.buf.write(b")")?;
-        self.buf.write(b"\n")?;
+    /// Leaves the script with the exit code `code` once `EXIT` is set, by
+    /// returning from the subroutine being compiled or by going to `END`
+    fn exit_with(&self, code: &str) -> String {
+        if self.sets_env && !self.in_subroutine {
+            format!("GOTO :{}", END)
+        } else if code.is_empty() {
+            "EXIT /B".to_owned()
+        } else {
+            format!("EXIT /B {}", code)
+        }
+    }
+
+    /// `env(name)` expands the environment variable, and `set_env(name,
+    /// value)` sets it
+    fn compile_env(&mut self, call: FuncCall) -> io::Result<()> {
+        let mut params = call.params.into_iter();
+        let name = env_name(&call.func_name, params.next())?;
+        match (call.func_name.as_str(), params.next(), params.next()) {
+            ("env", None, None) => self.read_var(&name)?,
+            ("set_env", Some(mut value), None) => {
+                self.hoist(&mut value)?;
+                self.compile_set(&name, value)?;
+                self.environment.insert(name);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("wrong number of arguments to `{}`", call.func_name),
+                ))
+            }
+        }
         Ok(())
     }
 
-    pub fn compile_binary_or(&mut self, ast: Box<If>) -> io::Result<()> {
+    /// `throw(message, [code])` raises an error, with an exit code of 1 if
+    /// none is given
+    fn compile_throw(&mut self, params: Vec<Expr>) -> io::Result<()> {
+        let mut params = params.into_iter();
+        let (mut message, code) = match (params.next(), params.next(), params.next()) {
+            (Some(message), code, None) => (message, code.unwrap_or(Expr::Int(1))),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`throw` takes a message and an optional exit code",
+                ))
+            }
+        };
+        self.hoist(&mut message)?;
+        self.compile_set(ERROR, message)?;
+        write!(self.buf, "SET /A {}=", STATUS)?;
+        self.compile_expr(code)?;
+        writeln!(self.buf, "\n{}", self.raise())?;
         Ok(())
     }
-    pub fn compile_expr(&mut self, ast: Expr) -> io::Result<()> {
-        match ast {
-            Expr::Binary(i) => self.compile_binary_expr(i)?,
-            Expr::Variable(i) => self.compile_variable(i)?,
-            Expr::Int(i) => write!(self.buf, "{}", i)?,
-            Expr::VariableDecl(i) => self.compile_var_decl(i)?,
-            Expr::Block(i) => self.compile_block(i)?,
-            Expr::For(i) => self.compile_for(i)?,
-            Expr::Str(i) => write!(self.buf, "{}", i)?,
-            Expr::FuncDef(i) => self.compile_func_def(i)?,
-            Expr::FuncCall(i) => self.compile_func_call(i)?,
-            Expr::Return(i) => self.compile_return(i)?,
-            Expr::Break => self.compile_break()?,
-            Expr::While(i) => self.compile_while(i)?,
-            Expr::Loop(i) => self.compile_loop(i)?,
-            Expr::Unary(i) => self.compile_unary_expr(i)?,
-            Expr::Continue => self.compile_continue()?,
-            Expr::ConstDecl(i) => self.compile_const_decl(i)?,
-            Expr::If(i) => self.compile_if(i)?,
-            Expr::Paren(i) => self.compile_expr(*i)?
+
+    /// The command that passes on the error in `ERROR`: a jump to the
+    /// innermost handler in this subroutine, or else a return to the caller
+    fn raise(&self) -> String {
+        match self.handlers.iter().rev().find_map(|h| h.label.as_ref()) {
+            Some(label) => format!("GOTO :{}", label),
+            None if self.in_subroutine => format!("EXIT /B %{}%", STATUS),
+            None => format!("GOTO :{}", UNCAUGHT),
+        }
+    }
+
+    /// Lowers `try` to labels, like `match`. An error raised in the `try`
+    /// block jumps to the `catch` block, both go on to the `finally` block,
+    /// and an error that is still set after that is passed on
+    pub fn compile_try(&mut self, ast: Box<Try>) -> io::Result<()> {
+        let label = self.new_label("try");
+        let catch_label = format!("{}_catch", label);
+        let finally_label = format!("{}_finally", label);
+        let Try {
+            body,
+            catch,
+            finally,
+            ..
+        } = *ast;
+        self.handlers.push(Handler {
+            label: Some(if catch.is_some() {
+                catch_label.clone()
+            } else {
+                finally_label.clone()
+            }),
+            has_finally: finally.is_some(),
+            loops: self.stack.len(),
+        });
+        self.trying += 1;
+        self.compile_expr(body)?;
+        self.trying -= 1;
+        if let Some((name, handler)) = catch {
+            writeln!(self.buf, "GOTO :{}", finally_label)?;
+            writeln!(self.buf, ":{}", catch_label)?;
+            let var = self.var_name(&name);
+            writeln!(self.buf, "SET \"{}=%{}%\"", var, ERROR)?;
+            writeln!(self.buf, "SET \"{}=\"", ERROR)?;
+            self.names.insert(var, name);
+            if let Some(h) = self.handlers.last_mut() {
+                h.label = finally.as_ref().map(|_| finally_label.clone());
+            }
+            self.compile_expr(handler)?;
+        }
+        self.handlers.pop();
+        writeln!(self.buf, ":{}", finally_label)?;
+        if let Some(finally) = finally {
+            // commands in the `finally` block overwrite the error's code
+            writeln!(self.buf, "SET \"{}_status=%{}%\"", label, STATUS)?;
+            self.compile_expr(finally)?;
+            writeln!(self.buf, "IF DEFINED {} SET \"{}=%{}_status%\"", ERROR, STATUS, label)?;
+            writeln!(self.buf, "IF DEFINED {} {}", ERROR, self.raise())?;
         }
         Ok(())
     }
-    pub fn compile_unary_expr(&mut self, ast: Box<UnaryExpr>) -> io::Result<()> {
-        self.compile_unary_op_kind(ast.op)?;
-        self.compile_expr(ast.child)?;
+
+    /// `break`, `continue` and `return` can't jump past a `finally` block,
+    /// as they don't go through its label
+    fn check_finally(&self, jump: &str, loops: usize) -> io::Result<()> {
+        if self.handlers.iter().any(|h| h.has_finally && h.loops >= loops) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` can't leave a `try` with a `finally` block", jump),
+            ));
+        }
         Ok(())
     }
-    pub fn compile_binary_expr(&mut self, ast: Box<BinaryExpr>) -> io::Result<()> {
-        self.compile_expr(ast.left)?;
-        self.compile_binary_op_kind(ast.op)?;
-        self.compile_expr(ast.right)?;
+
+    /// Collects the script's arguments into `ARGS` before anything else runs,
+    /// if the program uses them. `%1` only reaches `%9`, so arguments are
+    /// read one at a time with `SHIFT`. Arguments matching a declared flag
+    /// set its variable instead, and `--help` prints the flags.
+    fn compile_args(&mut self, ast: &Expr) -> io::Result<()> {
+        let mut declared = Vec::new();
+        let mut uses_args = false;
+        find_args(ast, &mut declared, &mut uses_args);
+        if declared.len() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a script can only declare `flags` once",
+            ));
+        }
+        let flags = match declared.pop() {
+            Some(flags) => flags.clone(),
+            None if uses_args => Vec::new(),
+            None => return Ok(()),
+        };
+        let label = self.new_label("args");
+        writeln!(self.buf, "SET \"{}=\"", ARGS)?;
+        writeln!(self.buf, "SET /A {}=0", ARGS_COUNT)?;
+        for flag in &flags {
+            let name = self.var_name(&flag.name);
+            self.names.insert(name.clone(), flag.name.clone());
+            match (&flag.kind, &flag.default) {
+                (FlagKind::Bool, Some(Expr::Bool(true))) => writeln!(self.buf, "SET \"{}=1\"", name)?,
+                (FlagKind::Bool, _) => writeln!(self.buf, "SET \"{}=0\"", name)?,
+                (_, Some(default)) => self.compile_set(&name, default.clone())?,
+                (_, None) => writeln!(self.buf, "SET \"{}=\"", name)?,
+            }
+        }
+        writeln!(self.buf, ":{}_next", label)?;
+        writeln!(self.buf, "IF [%1]==[] GOTO :{}_end", label)?;
+        if !flags.is_empty() {
+            writeln!(self.buf, "IF /I \"%~1\"==\"--help\" GOTO :{}_help", label)?;
+            writeln!(self.buf, "IF /I \"%~1\"==\"-h\" GOTO :{}_help", label)?;
+        }
+        for flag in &flags {
+            let name = self.var_name(&flag.name);
+            writeln!(self.buf, "IF /I \"%~1\"==\"--{}\" (", flag.name)?;
+            if flag.kind == FlagKind::Bool {
+                writeln!(self.buf, "SET \"{}=1\"", name)?;
+            } else {
+                self.buf.write_all(b"IF [%2]==[] (\n")?;
+                writeln!(self.buf, "ECHO(%~nx0: --{} needs a value 1>&2", flag.name)?;
+                self.buf.write_all(b"EXIT /B 1\n)\n")?;
+                writeln!(self.buf, "SET \"{}=%~2\"", name)?;
+                self.buf.write_all(b"SHIFT\n")?;
+            }
+            self.buf.write_all(b"SHIFT\n")?;
+            writeln!(self.buf, "GOTO :{}_next", label)?;
+            self.buf.write_all(b")\n")?;
+        }
+        writeln!(self.buf, "SET \"{}_arg=%~1\"", label)?;
+        if !flags.is_empty() {
+            writeln!(self.buf, "IF \"%{}_arg:~0,2%\"==\"--\" (", label)?;
+            self.buf.write_all(b"ECHO(%~nx0: unknown option \"%~1\", see --help 1>&2\n")?;
+            self.buf.write_all(b"EXIT /B 1\n)\n")?;
+        }
+        // the argument is only read with delayed expansion, so an `&` in a
+        // quoted argument can't run as cmd syntax
+        self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+        writeln!(self.buf, "{}", quote_item(&format!("{}_arg", label)))?;
+        writeln!(self.buf, "SET \"{0}_arg=!{1}! \"!{0}_arg!\"\"", label, ARGS)?;
+        self.end_delayed(&format!("{}_arg", label), ARGS)?;
+        writeln!(self.buf, "SET /A {}+=1", ARGS_COUNT)?;
+        self.buf.write_all(b"SHIFT\n")?;
+        writeln!(self.buf, "GOTO :{}_next", label)?;
+        if !flags.is_empty() {
+            writeln!(self.buf, ":{}_help", label)?;
+            self.buf.write_all(b"ECHO(Usage: %~nx0 [options] [args...]\n")?;
+            self.buf.write_all(b"ECHO(\nECHO(Options:\n")?;
+            for flag in flags {
+                write!(self.buf, "ECHO(  --{}", flag.name)?;
+                self.buf.write_all(match flag.kind {
+                    FlagKind::Bool => b"",
+                    FlagKind::Str => b" ^<str^>",
+                    FlagKind::Path => b" ^<path^>",
+                    FlagKind::Num => b" ^<num^>",
+                })?;
+                match flag.default {
+                    Some(default) if flag.kind != FlagKind::Bool => {
+                        self.buf.write_all(b" (default: ")?;
+                        self.in_echo = true;
+                        self.compile_expr(default)?;
+                        self.in_echo = false;
+                        self.buf.write_all(b")")?;
+                    }
+                    _ => {}
+                }
+                self.buf.write_all(b"\n")?;
+            }
+            self.buf.write_all(b"ECHO(  -h, --help\nEXIT /B 0\n")?;
+        }
+        writeln!(self.buf, ":{}_end", label)?;
         Ok(())
     }
 
-    pub fn compile_for(&mut self, ast: Box<For>) -> io::Result<()> {
-        self.stack.push(SCOPE.fetch_add(1, Ordering::Relaxed));
-        self.for_var.insert(ast.item.clone());
-        self.buf.write(b"FOR /l %%")?;
-        self.buf.write(ast.item.as_bytes())?;
-        self.buf.write(b" IN ")?;
-        self.compile_expr(ast.container)?;
-        self.buf.write(b" DO (\n")?;
-        self.compile_expr(ast.body)?;
-        self.buf.write(b")\n")?;
-        let popped = self.stack.pop().unwrap();
-        writeln!(self.buf, ":END{}", popped)?;
-        self.for_var.remove(&ast.item);
+    /// Lowers `if` to jumps like `while`, so the branches aren't
+    /// parenthesised and can hold labels and read values set inside them
+    pub fn compile_if(&mut self, ast: Box<If>) -> io::Result<()> {
+        let label = self.new_label("if");
+        let else_label = format!("{}_else", label);
+        let end = format!("{}_end", label);
+        let If {
+            mut cond,
+            then,
+            else_,
+            ..
+        } = *ast;
+        let has_else = else_ != Expr::Block(Vec::new());
+        self.hoist(&mut cond)?;
+        self.jump_unless(cond, if has_else { &else_label } else { &end })?;
+        self.compile_expr(then)?;
+        if has_else {
+            writeln!(self.buf, "GOTO :{}", end)?;
+            writeln!(self.buf, ":{}", else_label)?;
+            self.compile_expr(else_)?;
+        }
+        writeln!(self.buf, ":{}", end)?;
         Ok(())
     }
 
-    pub fn check_op(&mut self, ast: &Box<BinaryExpr>) -> &'static str {
-        match ast.op {
-            BinaryOpKind::BinaryAnd => "&&",
-            BinaryOpKind::BinaryOr =>  "||",
-            _ => ""
+    /// Lowers `match` to a chain of `IF ... GOTO` jumps to one label per arm,
+    /// so the value is only evaluated once and arms are not parenthesised
+    pub fn compile_match(&mut self, ast: Box<Match>) -> io::Result<()> {
+        let label = self.new_label("match");
+        let Match {
+            mut value,
+            arms,
+            default,
+            ignore_case,
+            ..
+        } = *ast;
+        self.hoist(&mut value)?;
+        self.compile_set(&format!("{}_value", label), value)?;
+        let mut bodies = Vec::with_capacity(arms.len());
+        for (i, arm) in arms.into_iter().enumerate() {
+            for pattern in arm.patterns {
+                self.buf.write_all(if ignore_case { b"IF /I " } else { b"IF " })?;
+                write!(self.buf, "\"%{}_value%\"==", label)?;
+                self.compile_quoted(pattern)?;
+                writeln!(self.buf, " GOTO :{}_arm_{}", label, i)?;
+            }
+            bodies.push(arm.body);
+        }
+        if default.is_some() {
+            writeln!(self.buf, "GOTO :{}_default", label)?;
+        } else {
+            writeln!(self.buf, "GOTO :{}_end", label)?;
+        }
+        for (i, body) in bodies.into_iter().enumerate() {
+            writeln!(self.buf, ":{}_arm_{}", label, i)?;
+            self.compile_expr(body)?;
+            writeln!(self.buf, "GOTO :{}_end", label)?;
         }
+        if let Some(body) = default {
+            writeln!(self.buf, ":{}_default", label)?;
+            self.compile_expr(body)?;
+        }
+        writeln!(self.buf, ":{}_end", label)?;
+        Ok(())
     }
-    pub fn compile_func_def(&mut self, ast: Box<FuncDef>) -> io::Result<()> {
-        self.buf.write(b":")?;
-        self.buf.write(ast.name.as_bytes())?;
-        self.buf.write(b" ")?;
-        if !ast.params.is_empty() {
-            self.compile_params_decl(ast.params)?;
+
+    pub fn compile_str(&mut self, ast: &str) -> io::Result<()> {
+        let mut escaped = String::with_capacity(ast.len());
+        for c in ast.chars() {
+            match c {
+                '%' => escaped.push_str("%%"),
+                '"' if self.in_array => escaped.push_str("\"\""),
+                // A line break can only be printed by starting another `ECHO`
+                '\n' if self.in_echo && !self.in_quotes => {
+                    escaped.push('\n');
+                    escaped.extend(self.echo_redirect.as_deref());
+                    escaped.push_str("ECHO(");
+                }
+                '\r' if self.in_echo && !self.in_quotes => {}
+                // With delayed expansion, cmd takes off another round of
+                // carets, inside quotes too, after reading `!name!`
+                '!' if self.delayed => {
+                    escaped.push_str(if self.in_quotes { "^" } else { "^^" });
+                    escaped.push(c);
+                }
+                '^' if self.delayed => escaped.push_str(if self.in_quotes { "^^" } else { "^^^^" }),
+                '^' | '&' | '|' | '<' | '>' if !self.in_quotes => {
+                    escaped.push('^');
+                    escaped.push(c);
+                }
+                _ => escaped.push(c),
+            }
         }
-        self.buf.write(b"\n")?;
-        self.compile_expr(ast.body)?;
-        self.buf.write(b"\n")?;
-        self.buf.write(b"EXIT /B 0")?;
+        self.buf.write_all(escaped.as_bytes())?;
         Ok(())
     }
 
-    pub fn compile_variable(&mut self, ast: String) -> io::Result<()> {
-        let t = match self.for_var.get(&ast) {
-            Some(v) => format!("%%{}", v),
-            None => format!("%{}%", ast)
-        };
-        write!(self.buf, "{}", t)?;
+    /// Writes `ast` wrapped in double quotes
+    pub fn compile_quoted(&mut self, ast: Expr) -> io::Result<()> {
+        let in_quotes = self.in_quotes;
+        self.in_quotes = true;
+        self.buf.write_all(b"\"")?;
+        self.compile_expr(ast)?;
+        self.buf.write_all(b"\"")?;
+        self.in_quotes = in_quotes;
         Ok(())
     }
 
-    pub fn compile_params_decl(&mut self, mut ast: Vec<String>) -> io::Result<()> {
-        let p = ast.pop();
-        for x in ast {
-            self.buf.write(x.as_bytes())?;
-            self.buf.write(b", ")?;
+    /// Writes `SET "name=value"`, which keeps the quotes out of the value
+    fn compile_set(&mut self, name: &str, value: Expr) -> io::Result<()> {
+        let delayed = !is_literal(&value);
+        if delayed {
+            self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+        }
+        let in_quotes = self.in_quotes;
+        self.in_quotes = true;
+        self.delayed = delayed;
+        write!(self.buf, "SET \"{}=", name)?;
+        self.compile_expr(value)?;
+        self.buf.write_all(b"\"\n")?;
+        self.delayed = false;
+        self.in_quotes = in_quotes;
+        if delayed {
+            self.end_delayed(name, name)?;
         }
-        let inside = p.unwrap();
-        self.buf.write(inside.as_bytes())?;
         Ok(())
     }
 
-    pub fn compile_params(&mut self, mut ast: Vec<Expr>) -> io::Result<()> { 
-        let p = ast.pop();
-        for x in ast {
-            self.compile_expr(x)?;
-            self.buf.write(b", ")?;
+    /// Expects computed parts to have already been moved into temporaries by
+    /// `hoist`
+    pub fn compile_interpolated(&mut self, ast: Vec<Expr>) -> io::Result<()> {
+        for part in ast {
+            self.compile_expr(part)?;
         }
-        self.compile_expr(p.unwrap())?;
         Ok(())
     }
 
-    pub fn compile_return(&mut self, ast: Box<Expr>) -> io::Result<()> {
-        self.buf.write(b"SET %~1=")?;
-        self.compile_expr(*ast)?;
+    /// Some values can only be computed by statements of their own: cmd can
+    /// only expand variables inside a string, so `{expr}` parts of
+    /// interpolated strings that compute a value, and commands run for their
+    /// exit code or output, are evaluated into temporaries before the
+    /// statement that uses them
+    fn hoist(&mut self, ast: &mut Expr) -> io::Result<()> {
+        // a backtick command used as a value is run for its exit code
+        if let Expr::Command(_) = ast {
+            let command = std::mem::replace(ast, Expr::Block(Vec::new()));
+            *ast = Expr::FuncCall(Box::new(FuncCall {
+                func_name: "run".to_owned(),
+                params: vec![command],
+            }));
+        }
+        match ast {
+            Expr::FuncCall(c) if c.func_name == "env_or" => {
+                let mut params = std::mem::take(&mut c.params).into_iter();
+                let name = env_name("env_or", params.next())?;
+                let mut default = match (params.next(), params.next()) {
+                    (Some(default), None) => default,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "`env_or` takes a name and a default value",
+                        ))
+                    }
+                };
+                self.hoist(&mut default)?;
+                let temp = self.new_label("tmp");
+                self.exported.insert(temp.clone());
+                self.compile_set(&temp, default)?;
+                writeln!(self.buf, "IF DEFINED {0} SET \"{1}=%{0}%\"", name, temp)?;
+                *ast = Expr::Variable(temp);
+            }
+            Expr::FuncCall(c) if is_command(&c.func_name) => {
+                self.hoist_command_params(&mut c.params)?;
+                let temp = self.new_label("tmp");
+                self.exported.insert(temp.clone());
+                if returns_array(&c.func_name) {
+                    self.arrays.insert(temp.clone());
+                }
+                if let Expr::FuncCall(call) = std::mem::replace(ast, Expr::Variable(temp.clone())) {
+                    self.compile_command(*call, Some(temp))?;
+                }
+            }
+            Expr::FuncCall(c) if c.func_name == "wait" => {
+                let params = std::mem::take(&mut c.params);
+                let temp = self.new_label("tmp");
+                self.exported.insert(temp.clone());
+                *ast = Expr::Variable(temp.clone());
+                self.compile_wait(params, Some(temp))?;
+            }
+            Expr::Spawn(_) => {
+                let temp = self.new_label("tmp");
+                self.exported.insert(temp.clone());
+                if let Expr::Spawn(body) = std::mem::replace(ast, Expr::Variable(temp.clone())) {
+                    self.compile_spawn(*body, Some(temp))?;
+                }
+            }
+            Expr::FuncCall(c) if self.externs.contains_key(&c.func_name) => {
+                for p in c.params.iter_mut() {
+                    self.hoist(p)?;
+                }
+                let temp = self.new_label("tmp");
+                self.exported.insert(temp.clone());
+                if let Expr::FuncCall(call) = std::mem::replace(ast, Expr::Variable(temp.clone())) {
+                    self.compile_extern_call(*call, Some(temp))?;
+                }
+            }
+            Expr::Interpolated(parts) => {
+                for part in parts.iter_mut() {
+                    self.hoist(part)?;
+                    match part {
+                        Expr::Unary(_) | Expr::Binary(_) | Expr::Paren(_) => {
+                            check_arithmetic(part)?;
+                            let temp = self.new_label("tmp");
+                            self.exported.insert(temp.clone());
+                            let computed = std::mem::replace(part, Expr::Variable(temp.clone()));
+                            let in_quotes = std::mem::replace(&mut self.in_quotes, true);
+                            write!(self.buf, "SET /A \"{}=", temp)?;
+                            self.compile_expr(computed)?;
+                            self.buf.write_all(b"\"\n")?;
+                            self.in_quotes = in_quotes;
+                        }
+                        Expr::FuncCall(c) if !is_value_builtin(&c.func_name) => {
+                            let temp = self.new_label("tmp");
+                            self.exported.insert(temp.clone());
+                            if let Expr::FuncCall(call) = std::mem::replace(part, Expr::Variable(temp.clone())) {
+                                self.compile_func_calll(call, Some(temp))?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Expr::Unary(u) => self.hoist(&mut u.child)?,
+            Expr::Binary(b) => {
+                self.hoist(&mut b.left)?;
+                self.hoist(&mut b.right)?;
+            }
+            Expr::Paren(e) => self.hoist(e)?,
+            Expr::Array(items) => {
+                for item in items.iter_mut() {
+                    self.hoist(item)?;
+                }
+            }
+            Expr::FuncCall(c) => {
+                for p in c.params.iter_mut() {
+                    self.hoist(p)?;
+                }
+            }
+            _ => {}
+        }
         Ok(())
     }
 
-    pub fn compile_break(&mut self) -> io::Result<()> {
-        let popped = self.stack.pop().unwrap();
-        self.stack.push(popped);
-        writeln!(self.buf, "GOTO :END{}", popped)?;
+    /// Hoists the arguments of `run`, `capture` or `capture_lines`. A
+    /// backtick command passed to them is only hoisted into, not run
+    fn hoist_command_params(&mut self, params: &mut [Expr]) -> io::Result<()> {
+        for p in params.iter_mut() {
+            match p {
+                Expr::Command(command) => {
+                    for stage in command.stages.iter_mut() {
+                        for word in stage.words.iter_mut() {
+                            self.hoist(word)?;
+                        }
+                        for target in stage.redirects.iter_mut().filter_map(|r| r.target.as_mut()) {
+                            self.hoist(target)?;
+                        }
+                    }
+                }
+                _ => self.hoist(p)?,
+            }
+        }
         Ok(())
     }
 
-    pub fn compile_continue(&mut self) -> io::Result<()> {
-        unimplemented!();
+    /// Runs `run(program, [args])`, or `capture` or `capture_lines` with the
+    /// same arguments, or with a backtick command instead. `run` leaves the
+    /// exit code in `result`. The captures
+    /// read the command's output with `FOR /F`, which skips empty lines, and
+    /// leave it in `result` as one string of space separated lines or as an
+    /// array of lines.
+    fn compile_command(&mut self, call: FuncCall, result: Option<String>) -> io::Result<()> {
+        let mut params = call.params.into_iter();
+        let mut line = Vec::new();
+        let program = match (params.next(), params.next(), params.next()) {
+            // `FINDSTR /N` numbers every line, so `FOR /F` doesn't skip empty
+            // lines or lines starting with `;`
+            (Some(path), None, None) if call.func_name == "read_lines" => {
+                line.push(CommandPart::Text("findstr /n \"^\" ".to_owned()));
+                self.command_arg(path, &mut line)?;
+                Expr::Str("findstr".to_owned())
+            }
+            (Some(path), None, None) if call.func_name == "list_dir" => {
+                line.push(CommandPart::Text("dir /b /a ".to_owned()));
+                self.command_arg(path, &mut line)?;
+                Expr::Str("dir".to_owned())
+            }
+            (_, _, _) if matches!(call.func_name.as_str(), "read_lines" | "list_dir") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` takes a path", call.func_name),
+                ))
+            }
+            (Some(Expr::Command(command)), None, None) => self.pipeline(*command, &mut line)?,
+            (Some(program), args, None) => {
+                self.command_arg(program.clone(), &mut line)?;
+                match args {
+                    Some(Expr::Array(items)) => {
+                        for item in items {
+                            line.push(CommandPart::Text(" ".to_owned()));
+                            self.command_arg(item, &mut line)?;
+                        }
+                    }
+                    None => {}
+                    Some(args) => match self.array_arg(&args) {
+                        Some(items) => {
+                            line.push(CommandPart::Text(" ".to_owned()));
+                            line.push(items);
+                        }
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "command arguments must be an array",
+                            ))
+                        }
+                    },
+                }
+                program
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "`{}` takes a program and an array of arguments, or a command",
+                        call.func_name
+                    ),
+                ))
+            }
+        };
+        if call.func_name == "run" {
+            self.write_command(&line)?;
+            self.buf.write_all(b"\n")?;
+            return self.compile_status(program, result);
+        }
+
+        let label = self.new_label("capture");
+        let result = result.unwrap_or_else(|| format!("{}_output", label));
+        let var = self.for_var()?;
+        writeln!(self.buf, "SET \"{}=\"", result)?;
+        write!(self.buf, "FOR /F \"usebackq delims=\" %%{} IN (`", var)?;
+        // `FOR /F` runs the command with `cmd /c`, which strips the outer
+        // quotes off a command line that starts with one
+        line.insert(0, CommandPart::Text("\"".to_owned()));
+        line.push(CommandPart::Text("\"".to_owned()));
+        self.write_command(&line)?;
+        writeln!(self.buf, "`) DO (")?;
+        writeln!(self.buf, "SET \"{}_line=%%{}\"", label, var)?;
+        writeln!(self.buf, "CALL :{}_add", label)?;
+        self.buf.write_all(b")\n")?;
+        writeln!(self.buf, "GOTO :{}_end", label)?;
+        writeln!(self.buf, ":{}_add", label)?;
+        // the line is only ever read with delayed expansion, so an `&` or `>`
+        // in the output can't run as cmd syntax
+        self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+        if call.func_name == "read_lines" {
+            writeln!(self.buf, "SET \"{0}_line=!{0}_line:*:=!\"", label)?;
+        }
+        if returns_array(&call.func_name) {
+            writeln!(self.buf, "{}", quote_item(&format!("{}_line", label)))?;
+            writeln!(self.buf, "SET \"{1}_line=!{0}! \"!{1}_line!\"\"", result, label)?;
+        } else {
+            writeln!(self.buf, "IF DEFINED {0} SET \"{1}_line=!{0}! !{1}_line!\"", result, label)?;
+        }
+        self.end_delayed(&format!("{}_line", label), &result)?;
+        self.buf.write_all(b"EXIT /B 0\n")?;
+        writeln!(self.buf, ":{}_end", label)?;
         Ok(())
     }
 
-    pub fn compile_const_decl(&mut self, ast: Box<ConstDecl>) -> io::Result<()> {
-        unimplemented!();
+    /// Calls a batch file declared with `extern fn`, quoting each argument
+    /// like a command's. With `returns`, the batch file's variable is cleared
+    /// before the call and copied to `result` after it, and otherwise the exit
+    /// code is
+    fn compile_extern_call(&mut self, call: FuncCall, result: Option<String>) -> io::Result<()> {
+        let ext = self.externs[&call.func_name].clone();
+        if call.params.len() != ext.params.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "wrong number of arguments to `{}`: expected {}, got {}",
+                    ext.name,
+                    ext.params.len(),
+                    call.params.len()
+                ),
+            ));
+        }
+        let mut line = Vec::new();
+        for arg in call.params {
+            if matches!(arg, Expr::Array(_)) || self.array_arg(&arg).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` is a batch file, which can't be passed an array", ext.name),
+                ));
+            }
+            line.push(CommandPart::Text(" ".to_owned()));
+            self.command_arg(arg, &mut line)?;
+        }
+        if let Some(ref var) = ext.result {
+            writeln!(self.buf, "SET \"{}=\"", var)?;
+        }
+        write!(self.buf, "CALL \"{}\"", batch_path(&ext.path))?;
+        if let Some(ref label) = ext.label {
+            write!(self.buf, " :{}", label)?;
+        }
+        self.write_command(&line)?;
+        self.buf.write_all(b"\n")?;
+        let program = Expr::Str(ext.path);
+        match (ext.result, result) {
+            (Some(var), Some(result)) => {
+                self.compile_status(program, None)?;
+                writeln!(self.buf, "SET \"{}=%{}%\"", result, var)?;
+            }
+            (_, result) => self.compile_status(program, result)?,
+        }
         Ok(())
     }
-    pub fn compile_var_decl(&mut self, ast: Box<VariableDecl>) -> io::Result<()> {
-        self.buf.write(b"SET ")?;
-        if ast.is_numeric {
-            self.buf.write(b"/A ")?;
+
+    /// Runs `body` in a process of its own, started with `START /B` running
+    /// the file again at `SPAWNED`. `handle` is set to the path of the file
+    /// that the process writes its exit code to when it is done, which is
+    /// the exit code of the last command or call in `body`
+    fn compile_spawn(&mut self, body: Expr, handle: Option<String>) -> io::Result<()> {
+        let label = self.new_label("spawn");
+        if let Some(ref handle) = handle {
+            writeln!(self.buf, "SET \"{}=%TEMP%\\{}_%RANDOM%%RANDOM%\"", handle, label)?;
         }
-        self.buf.write(ast.name.as_bytes())?;
-        self.buf.write(b"=")?;
-        self.compile_expr(ast.value)?;
-        self.buf.write(b"\n")?;
+        write!(self.buf, "START \"\" /B cmd /D /C CALL \"%~f0\" :{} {}", SPAWNED, label)?;
+        if let Some(ref handle) = handle {
+            write!(self.buf, " \"%{}%\"", handle)?;
+        }
+        self.buf.write_all(b"\n")?;
+        writeln!(self.buf, "GOTO :{}_end", label)?;
+        writeln!(self.buf, ":{}", label)?;
+        writeln!(self.buf, "SET \"{}=0\"", STATUS)?;
+        let stack = std::mem::take(&mut self.stack);
+        let handlers = std::mem::take(&mut self.handlers);
+        let trying = std::mem::replace(&mut self.trying, 0);
+        let in_subroutine = std::mem::replace(&mut self.in_subroutine, true);
+        let tracks_status = std::mem::replace(&mut self.tracks_status, true);
+        self.compile_expr(body)?;
+        self.stack = stack;
+        self.handlers = handlers;
+        self.trying = trying;
+        self.in_subroutine = in_subroutine;
+        self.tracks_status = tracks_status;
+        writeln!(self.buf, "EXIT /B %{}%", STATUS)?;
+        writeln!(self.buf, ":{}_end", label)?;
         Ok(())
     }
 
-    pub fn compile_block(&mut self, ast: Vec<Expr>) -> io::Result<()> {
-        for x in ast {
-            self.compile_expr(x)?;
+    /// `wait(handle)` waits for a spawned process to finish, checking for its
+    /// file once a second, and reads its exit code like a command's
+    fn compile_wait(&mut self, mut params: Vec<Expr>, result: Option<String>) -> io::Result<()> {
+        let mut handle = match (params.pop(), params.is_empty()) {
+            (Some(handle), true) => handle,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "`wait` takes the handle of a `spawn`",
+                ))
+            }
+        };
+        self.hoist(&mut handle)?;
+        let label = self.new_label("wait");
+        let file = format!("{}_file", label);
+        let code = format!("{}_code", label);
+        self.exported.insert(file.clone());
+        self.compile_set(&file, handle)?;
+        writeln!(self.buf, ":{}", label)?;
+        writeln!(self.buf, "IF EXIST \"%{}%\" GOTO :{}_done", file, label)?;
+        // `TIMEOUT` fails when input is redirected, and `PING` waits a second
+        // between its two pings either way
+        self.buf.write_all(b"PING -n 2 127.0.0.1 >NUL\n")?;
+        writeln!(self.buf, "GOTO :{}", label)?;
+        writeln!(self.buf, ":{}_done", label)?;
+        writeln!(self.buf, "SET /P \"{}=\" <\"%{}%\"", code, file)?;
+        writeln!(self.buf, "DEL \"%{}%\"", file)?;
+        self.compile_code(Expr::Str("spawned process".to_owned()), &code, result)
+    }
+
+    /// Follows a command that was just run by `program`, storing its exit
+    /// code in `result`. If the code isn't stored, a failure raises an
+    /// error inside a `try` block or in fail fast mode
+    fn compile_status(&mut self, program: Expr, result: Option<String>) -> io::Result<()> {
+        self.compile_code(program, "ERRORLEVEL", result)
+    }
+
+    /// Like `compile_status`, with the exit code in the variable `code`
+    fn compile_code(&mut self, program: Expr, code: &str, result: Option<String>) -> io::Result<()> {
+        let checked = result.is_none() && (self.fail_fast || self.trying > 0);
+        if result.is_some() || checked || self.tracks_status {
+            writeln!(self.buf, "SET \"{}=%{}%\"", STATUS, code)?;
+        }
+        if let Some(result) = result {
+            writeln!(self.buf, "SET \"{}=%{}%\"", result, STATUS)?;
+        } else if checked {
+            let in_quotes = self.in_quotes;
+            self.in_quotes = true;
+            write!(self.buf, "IF %{}% NEQ 0 (SET \"{}=", STATUS, ERROR)?;
+            self.compile_expr(program)?;
+            write!(self.buf, " exited with code %{}%\" & ", STATUS)?;
+            writeln!(self.buf, "{})", self.raise())?;
+            self.in_quotes = in_quotes;
         }
         Ok(())
     }
 
-    pub fn compile_while(&mut self, ast: Box<While>) -> io::Result<()> {
-        let num = SCOPE.fetch_add(1, Ordering::Relaxed);
-        self.stack.push(num);
-        write!(self.buf, ":START{}\nIF ", num)?;
-        self.compile_expr(ast.cond)?;
-        self.buf.write(b" (\n")?;
-        self.compile_expr(ast.body)?;
-        let popped = self.stack.pop().unwrap();
-        writeln!(self.buf, "GOTO :START{}", popped)?;
-        self.buf.write(b"\n)\n")?;
-        writeln!(self.buf, ":END{}", popped)?;
+    /// `write_file(path, text)` and `append_file(path, text)` write the text
+    /// and a line break with `ECHO`. Every line of the text is an `ECHO` of
+    /// its own, which appends to the file
+    fn compile_write_file(&mut self, call: FuncCall) -> io::Result<()> {
+        let mut params = call.params.into_iter();
+        let (mut path, text) = match (params.next(), params.next(), params.next()) {
+            (Some(path), Some(text), None) => (path, text),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` takes a path and the text to write", call.func_name),
+                ))
+            }
+        };
+        self.hoist(&mut path)?;
+        let file = self.new_label("tmp");
+        self.exported.insert(file.clone());
+        self.compile_set(&file, path)?;
+        let append = format!(">>\"%{}%\" ", file);
+        let first = if call.func_name == "write_file" {
+            format!(">\"%{}%\" ", file)
+        } else {
+            append.clone()
+        };
+        self.echo_redirect = Some(append);
+        self.echo(vec![text], &first)?;
+        self.echo_redirect = None;
         Ok(())
     }
 
-    pub fn compile_loop(&mut self, ast: Box<Loop>) -> io::Result<()> {
-        let num = SCOPE.fetch_add(1, Ordering::Relaxed);
-        self.stack.push(num);
-        writeln!(self.buf, ":START{}", num)?;
-        self.compile_expr(ast.body)?;
-        let popped = self.stack.pop().unwrap();
-        writeln!(self.buf, "GOTO :START{}", popped)?;
-        writeln!(self.buf, ":END{}", popped)?;
+    /// `mkdir(path)`, `remove(path)`, `copy(from, to)` and `move(from, to)`.
+    /// `mkdir` also creates missing parent directories and does nothing if
+    /// the directory exists, and `remove` deletes a file or a whole
+    /// directory. `copy` and `move` fail like `run` does
+    fn compile_file_op(&mut self, mut call: FuncCall) -> io::Result<()> {
+        for p in call.params.iter_mut() {
+            self.hoist(p)?;
+        }
+        let name = call.func_name.as_str();
+        let mut params = call.params.into_iter();
+        match (name, params.next(), params.next(), params.next()) {
+            ("mkdir", Some(path), None, None) => {
+                self.buf.write_all(b"IF NOT ")?;
+                self.compile_exists(path.clone(), true)?;
+                self.buf.write_all(b" MD ")?;
+                self.compile_quoted(path)?;
+                self.buf.write_all(b"\n")?;
+            }
+            ("remove", Some(path), None, None) => {
+                self.buf.write_all(b"IF ")?;
+                self.compile_exists(path.clone(), true)?;
+                self.buf.write_all(b" (RD /S /Q ")?;
+                self.compile_quoted(path.clone())?;
+                self.buf.write_all(b") ELSE IF ")?;
+                self.compile_exists(path.clone(), false)?;
+                self.buf.write_all(b" DEL /F /Q ")?;
+                self.compile_quoted(path)?;
+                self.buf.write_all(b"\n")?;
+            }
+            ("copy", Some(from), Some(to), None) | ("move", Some(from), Some(to), None) => {
+                write!(self.buf, "{} /Y ", name.to_uppercase())?;
+                self.compile_quoted(from)?;
+                self.buf.write_all(b" ")?;
+                self.compile_quoted(to)?;
+                self.buf.write_all(b" >NUL\n")?;
+                self.compile_status(Expr::Str(name.to_owned()), None)?;
+            }
+            _ => {
+                let takes = match name {
+                    "copy" | "move" => "a source and a destination path",
+                    _ => "a path",
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` takes {}", name, takes),
+                ));
+            }
+        }
         Ok(())
-    } 
+    }
 
-    pub fn compile_binary_op_kind(&mut self, ast: BinaryOpKind) -> io::Result<()> {
-        self.buf.write(match ast {
-            BinaryOpKind::Add => b"+",
-            BinaryOpKind::Sub => b"-",
-            BinaryOpKind::Mul => b"*",
-            BinaryOpKind::Div => b"/",
-            BinaryOpKind::Assign => b"=",
-            BinaryOpKind::Eq => b" EQU ",
-            BinaryOpKind::Ne => b" NEQ ",
-            BinaryOpKind::Gt => b" GTR ",
-            BinaryOpKind::Lt => b" LSS ",
-            BinaryOpKind::GtEq => b" GEQ ",
-            BinaryOpKind::LtEq => b" LEQ ",
-            BinaryOpKind::Shr => b">>",
-            BinaryOpKind::Shl => b"<<",
-            BinaryOpKind::Xor => b"^",
-            BinaryOpKind::LogicalAnd => b"AND",
-            BinaryOpKind::LogicalOr => b"OR",
-            BinaryOpKind::BinaryAnd => b"&",
-            BinaryOpKind::BinaryOr => b"|",
-        })?;
+    /// Writes the condition `EXIST "path"`. A directory is checked for with
+    /// `"path\*"`, which only exists if `path` is a directory
+    fn compile_exists(&mut self, path: Expr, is_dir: bool) -> io::Result<()> {
+        let in_quotes = self.in_quotes;
+        self.in_quotes = true;
+        self.buf.write_all(b"EXIST \"")?;
+        self.compile_expr(path)?;
+        if is_dir {
+            self.buf.write_all(b"\\*")?;
+        }
+        self.buf.write_all(b"\"")?;
+        self.in_quotes = in_quotes;
         Ok(())
     }
-    pub fn compile_unary_op_kind(&mut self, ast: UnaryOpKind) -> io::Result<()> {
-        self.buf.write(match ast {
-            UnaryOpKind::Minus => b"-",
-            UnaryOpKind::LogicalNot => b"NOT",
-            UnaryOpKind::BitwiseNot => b"~",
-        })?;
+
+    /// Adds a backtick command to `line`, returning the program of its last
+    /// stage, whose exit code is the pipeline's. Words are quoted like the
+    /// arguments of `run`, and only the pipes and redirections are written
+    /// as they are
+    fn pipeline(&mut self, command: Command, line: &mut Vec<CommandPart>) -> io::Result<Expr> {
+        let mut program = Expr::Str(String::new());
+        for (i, stage) in command.stages.into_iter().enumerate() {
+            if i > 0 {
+                line.push(CommandPart::Operator(" | "));
+            }
+            for (j, word) in stage.words.into_iter().enumerate() {
+                if j == 0 {
+                    program = word.clone();
+                } else {
+                    line.push(CommandPart::Text(" ".to_owned()));
+                }
+                // `{files}` on its own passes each item of an array
+                let items = match word {
+                    Expr::Interpolated(ref parts) if parts.len() == 1 => self.array_arg(&parts[0]),
+                    _ => None,
+                };
+                match items {
+                    Some(items) => line.push(items),
+                    None => self.command_arg(word, line)?,
+                }
+            }
+            for redirect in stage.redirects {
+                line.push(CommandPart::Operator(match redirect.kind {
+                    RedirectKind::In => " < ",
+                    RedirectKind::Out => " > ",
+                    RedirectKind::Append => " >> ",
+                    RedirectKind::Err => " 2> ",
+                    RedirectKind::ErrAppend => " 2>> ",
+                    RedirectKind::ErrToOut => " 2>&1",
+                }));
+                if let Some(target) = redirect.target {
+                    self.command_arg(target, line)?;
+                }
+            }
+        }
+        Ok(program)
+    }
+
+    /// The variable holding an array passed as command arguments. Arrays are
+    /// already a list of quoted items
+    fn array_arg(&self, arg: &Expr) -> Option<CommandPart> {
+        match arg {
+            Expr::Variable(name) if self.arrays.contains(name) => {
+                Some(CommandPart::Var(self.var_name(name)))
+            }
+            Expr::FuncCall(c) if c.func_name == "args" => Some(CommandPart::Var(ARGS.to_owned())),
+            _ => None,
+        }
+    }
+
+    /// Adds one argument of a command line to `line`, quoted unless it's a
+    /// plain word. cmd doesn't run its internal commands like `dir` when they
+    /// are quoted, nor read their quoted switches
+    fn command_arg(&mut self, arg: Expr, line: &mut Vec<CommandPart>) -> io::Result<()> {
+        let parts = match arg {
+            Expr::Str(s) if is_plain_word(&s) => {
+                line.push(CommandPart::Text(s));
+                return Ok(());
+            }
+            Expr::Interpolated(parts) => parts,
+            arg => vec![arg],
+        };
+        line.push(CommandPart::Text("\"".to_owned()));
+        let count = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            match part {
+                Expr::Str(s) => line.push(CommandPart::Text(escape_arg(&s, i + 1 == count))),
+                Expr::Int(n) => line.push(CommandPart::Text(n.to_string())),
+                Expr::Bool(b) => line.push(CommandPart::Text((b as u8).to_string())),
+                Expr::Variable(name) => line.push(CommandPart::Var(self.var_name(&name))),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "command arguments must be strings, numbers or variables",
+                    ))
+                }
+            }
+        }
+        line.push(CommandPart::Text("\"".to_owned()));
         Ok(())
     }
+
+    /// Writes a command line, escaping cmd's special characters wherever cmd
+    /// will see them outside of double quotes
+    fn write_command(&mut self, line: &[CommandPart]) -> io::Result<()> {
+        let mut quoted = false;
+        for part in line {
+            match part {
+                CommandPart::Var(name) => write!(self.buf, "%{}%", name)?,
+                CommandPart::Operator(op) => self.buf.write_all(op.as_bytes())?,
+                CommandPart::Text(text) => {
+                    let mut escaped = String::with_capacity(text.len());
+                    for c in text.chars() {
+                        match c {
+                            '"' => quoted = !quoted,
+                            '%' => escaped.push('%'),
+                            '^' | '&' | '|' | '<' | '>' | '(' | ')' if !quoted => escaped.push('^'),
+                            _ => {}
+                        }
+                        escaped.push(c);
+                    }
+                    self.buf.write_all(escaped.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile_echo(&mut self, ast: Vec<Expr>) -> io::Result<()> {
+        self.echo(ast, "")
+    }
+
+    /// Prints `ast` with an `ECHO` that starts with `redirect`. Values are
+    /// read with delayed expansion, so a printed `&` is never run
+    fn echo(&mut self, mut ast: Vec<Expr>, redirect: &str) -> io::Result<()> {
+        for x in ast.iter_mut() {
+            self.hoist(x)?;
+        }
+        let delayed = !ast.iter().all(is_literal);
+        if delayed {
+            self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+        }
+        // `ECHO(` rather than `ECHO ` prints empty lines and text like "off" as is
+        write!(self.buf, "{}ECHO(", redirect)?;
+        self.in_echo = true;
+        self.delayed = delayed;
+        self.compile_block(ast)?;
+        self.delayed = false;
+        self.in_echo = false;
+        self.buf.write_all(b"\n")?;
+        if delayed {
+            self.buf.write_all(b"ENDLOCAL\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn compile_expr(&mut self, ast: Expr) -> io::Result<()> {
+        match ast {
+            Expr::Binary(i) => self.compile_binary_expr(i)?,
+            Expr::Variable(i) => self.compile_variable(i)?,
+            Expr::Int(i) => {
+                check_int(i)?;
+                write!(self.buf, "{}", i)?
+            }
+            // cmd has no boolean type, so booleans are stored as `1` or `0`
+            // like flags, and compared with `1` by `compile_cond`
+            Expr::Bool(b) => write!(self.buf, "{}", b as u8)?,
+            Expr::Array(i) => self.compile_array(i)?,
+            Expr::VariableDecl(i) => self.compile_var_decl(i)?,
+            Expr::Block(i) => self.compile_block(i)?,
+            Expr::For(i) => self.compile_for(i)?,
+            Expr::Str(i) => self.compile_str(&i)?,
+            Expr::Interpolated(i) => self.compile_interpolated(i)?,
+            Expr::FuncDef(i) => self.compile_func_def(i)?,
+            Expr::FuncCall(i) => self.compile_func_call(i)?,
+            Expr::Return(i) => self.compile_return(i)?,
+            Expr::Break => self.compile_break()?,
+            Expr::While(i) => self.compile_while(i)?,
+            Expr::Loop(i) => self.compile_loop(i)?,
+            Expr::Unary(i) => self.compile_unary_expr(i)?,
+            Expr::Continue => self.compile_continue()?,
+            Expr::If(i) => self.compile_if(i)?,
+            Expr::Match(i) => self.compile_match(i)?,
+            Expr::Try(i) => self.compile_try(i)?,
+            Expr::Command(i) => self.compile_func_call(Box::new(FuncCall {
+                func_name: "run".to_owned(),
+                params: vec![Expr::Command(i)],
+            }))?,
+            // parsed by `compile_args` before the program runs
+            Expr::Flags(_) => {}
+            Expr::Import(path) => {
+                self.imports.insert(module::name(Path::new(&path))?);
+            }
+            Expr::Use(i) => self.compile_use(*i)?,
+            // found by `find_externs` before the file is compiled
+            Expr::Extern(_) => {}
+            Expr::Spawn(i) => self.compile_spawn(*i, None)?,
+            Expr::Paren(i) => self.compile_expr(*i)?
+        }
+        Ok(())
+    }
+    /// Compiles an expression that follows `IF`. Flags and booleans are
+    /// stored as `1` or `0`, which `IF` can't test on its own
+    fn compile_cond(&mut self, ast: Expr) -> io::Result<()> {
+        match ast {
+            Expr::Bool(b) => write!(self.buf, "1 EQU {}", b as u8),
+            Expr::Variable(i) => {
+                self.compile_variable(i)?;
+                self.buf.write_all(b" EQU 1")
+            }
+            Expr::Unary(i) if i.op == UnaryOpKind::LogicalNot => {
+                self.buf.write_all(b"NOT ")?;
+                self.compile_cond(i.child)
+            }
+            Expr::Paren(i) => self.compile_cond(*i),
+            ast => self.compile_expr(ast),
+        }
+    }
+
+    pub fn compile_unary_expr(&mut self, ast: Box<UnaryExpr>) -> io::Result<()> {
+        self.compile_unary_op_kind(ast.op)?;
+        self.compile_expr(ast.child)?;
+        Ok(())
+    }
+    pub fn compile_binary_expr(&mut self, ast: Box<BinaryExpr>) -> io::Result<()> {
+        let is_root = !self.in_const;
+        if is_root {
+            if let Some(value) = const_value(&ast) {
+                check_int(value?)?;
+                self.in_const = true;
+            }
+        }
+        self.compile_expr(ast.left)?;
+        self.compile_binary_op_kind(ast.op)?;
+        self.compile_expr(ast.right)?;
+        if is_root {
+            self.in_const = false;
+        }
+        Ok(())
+    }
+
+    pub fn compile_for(&mut self, ast: Box<For>) -> io::Result<()> {
+        let label = self.new_label("for");
+        let For {
+            item,
+            mut container,
+            body,
+            ..
+        } = *ast;
+        if let Expr::FuncCall(ref c) = container {
+            if is_command(&c.func_name) {
+                self.hoist(&mut container)?;
+            }
+        }
+        if !self.container_is_for_command(&container) {
+            return self.compile_for_chars(&label, &item, container, body);
+        }
+        let var = self.for_var()?;
+        // Lines are taken verbatim, everything else may be quoted
+        let mut expansion = format!("%%~{}", var);
+        let mut items = Items::Plain;
+        writeln!(self.buf, "SET \"{}_break=\"", label)?;
+        match container {
+            Expr::FuncCall(call) => {
+                let mut params = call.params.into_iter();
+                match (call.func_name.as_str(), params.next(), params.next(), params.next()) {
+                    ("range", Some(start), Some(end), step) => {
+                        let step = step.unwrap_or(Expr::Int(1));
+                        self.compile_for_range(&label, var, start, end, step)?;
+                    }
+                    ("lines", Some(path), None, None) => {
+                        expansion = format!("%%{}", var);
+                        items = Items::Numbered;
+                        write!(self.buf, "FOR /F \"delims=\" %%{} IN ('findstr /n \"^\" ", var)?;
+                        self.compile_quoted(path)?;
+                        self.buf.write_all(b"')")?;
+                    }
+                    ("args", None, None, None) => {
+                        items = Items::Array;
+                        write!(self.buf, "FOR %%{} IN (%{}%)", var, ARGS)?;
+                    }
+                    ("glob", Some(pattern), None, None) => {
+                        write!(self.buf, "FOR %%{} IN (", var)?;
+                        self.compile_quoted(pattern)?;
+                        self.buf.write_all(b")")?;
+                    }
+                    ("output", Some(command), None, None) => {
+                        expansion = format!("%%{}", var);
+                        items = Items::Numbered;
+                        write!(self.buf, "FOR /F \"usebackq delims=\" %%{} IN (`", var)?;
+                        match command {
+                            // written inline, since `run` would end the line
+                            Expr::Command(command) => {
+                                let mut line = vec![CommandPart::Text("\"".to_owned())];
+                                self.pipeline(*command, &mut line)?;
+                                line.push(CommandPart::Operator(" | "));
+                                line.push(CommandPart::Text("findstr /n \"^\"\"".to_owned()));
+                                self.write_command(&line)?;
+                            }
+                            command => {
+                                self.compile_expr(command)?;
+                                self.buf.write_all(b" ^| findstr /n \"^\"")?;
+                            }
+                        }
+                        self.buf.write_all(b"`)")?;
+                    }
+                    (name, ..) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("cannot iterate over `{}(...)`", name),
+                        ))
+                    }
+                }
+            }
+            container => {
+                items = Items::Array;
+                write!(self.buf, "FOR %%{} IN (", var)?;
+                self.compile_expr(container)?;
+                self.buf.write_all(b")")?;
+            }
+        }
+        self.compile_for_body(&label, &item, &expansion, items, body)
+    }
+
+    /// Ends a `SETLOCAL EnableDelayedExpansion` by copying the variable
+    /// `value` out of it into `name`. `FOR /F` inserts the value after cmd
+    /// has parsed the line, so unlike `%value%` its special characters are
+    /// never run as cmd syntax. The value is wrapped in an extra pair of
+    /// quotes, which `%%~` takes off again, so that `FOR /F` never skips it
+    /// as empty or as a comment starting with `;`
+    fn end_delayed(&mut self, value: &str, name: &str) -> io::Result<()> {
+        self.for_depth += 1;
+        let var = self.for_var();
+        self.for_depth -= 1;
+        writeln!(
+            self.buf,
+            "FOR /F \"delims=\" %%{0} IN (\"\"!{1}!\"\") DO ENDLOCAL & SET \"{2}=%%~{0}\"",
+            var?, value, name
+        )
+    }
+
+    /// The `FOR` variable that is free at the current nesting depth
+    fn for_var(&self) -> io::Result<char> {
+        match FOR_VARS.get(self.for_depth) {
+            Some(&v) => Ok(v as char),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`for` loops are nested too deeply for cmd",
+            )),
+        }
+    }
+
+    /// Whether `container` is iterated with a cmd `FOR` command, rather than
+    /// being treated as a string
+    fn container_is_for_command(&self, container: &Expr) -> bool {
+        match container {
+            Expr::FuncCall(_) | Expr::Array(_) => true,
+            Expr::Variable(name) => self.arrays.contains(name),
+            _ => false,
+        }
+    }
+
+    /// Writes the `FOR /L` header for `range(start, end, step)`. `end` is
+    /// exclusive, while the end of a `FOR /L` is inclusive
+    fn compile_for_range(
+        &mut self,
+        label: &str,
+        var: char,
+        start: Expr,
+        end: Expr,
+        step: Expr,
+    ) -> io::Result<()> {
+        let last = match (int_literal(&end), int_literal(&step)) {
+            (Some(end), Some(step)) if step < 0 => (end + 1).to_string(),
+            (Some(end), Some(_)) => (end - 1).to_string(),
+            (_, step_literal) => {
+                let is_negative = matches!(step_literal, Some(s) if s < 0);
+                write!(self.buf, "SET /A {}_last=", label)?;
+                self.compile_expr(end.clone())?;
+                self.buf.write_all(if is_negative { b"+1\n" } else { b"-1\n" })?;
+                if step_literal.is_none() {
+                    self.buf.write_all(b"IF ")?;
+                    self.compile_expr(step.clone())?;
+                    write!(self.buf, " LSS 0 SET /A {}_last=", label)?;
+                    self.compile_expr(end)?;
+                    self.buf.write_all(b"+1\n")?;
+                }
+                format!("%{}_last%", label)
+            }
+        };
+        write!(self.buf, "FOR /L %%{} IN (", var)?;
+        self.compile_expr(start)?;
+        self.buf.write_all(b",")?;
+        self.compile_expr(step)?;
+        write!(self.buf, ",{})", last)?;
+        Ok(())
+    }
+
+    /// Finishes a loop that lowers to a cmd `FOR`. The body becomes a
+    /// subroutine that is `CALL`ed for each item after copying the single
+    /// letter `FOR` variable into the ski variable `item`, so the body sees
+    /// fresh values on every iteration and can leave with `EXIT /B`
+    fn compile_for_body(
+        &mut self,
+        label: &str,
+        item: &str,
+        expansion: &str,
+        items: Items,
+        body: Expr,
+    ) -> io::Result<()> {
+        let name = self.var_name(item);
+        self.names.insert(name.clone(), item.to_owned());
+        write!(self.buf, " DO IF NOT DEFINED {}_break", label)?;
+        if self.exits {
+            write!(self.buf, " IF NOT DEFINED {}", EXIT)?;
+        }
+        if self.raises {
+            write!(self.buf, " IF NOT DEFINED {}", ERROR)?;
+        }
+        self.buf.write_all(b" (\n")?;
+        writeln!(self.buf, "SET \"{}={}\"", name, expansion)?;
+        writeln!(self.buf, "CALL :{}_body", label)?;
+        self.buf.write_all(b")\n")?;
+        writeln!(self.buf, "GOTO :{}_end", label)?;
+        writeln!(self.buf, ":{}_body", label)?;
+        match items {
+            Items::Plain => {}
+            Items::Numbered => {
+                self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+                writeln!(self.buf, "SET \"{0}=!{0}:*:=!\"", name)?;
+                self.end_delayed(&name, &name)?;
+            }
+            Items::Array => {
+                self.buf.write_all(b"SETLOCAL EnableDelayedExpansion\n")?;
+                writeln!(self.buf, "IF DEFINED {0} SET \"{0}=!{0}:\"\"=\"!\"", name)?;
+                self.end_delayed(&name, &name)?;
+            }
+        }
+        self.stack.push(Jump::Subroutine {
+            break_flag: format!("{}_break", label),
+        });
+        self.for_depth += 1;
+        let handlers = std::mem::take(&mut self.handlers);
+        let in_subroutine = std::mem::replace(&mut self.in_subroutine, true);
+        self.compile_expr(body)?;
+        self.handlers = handlers;
+        self.in_subroutine = in_subroutine;
+        self.for_depth -= 1;
+        self.stack.pop();
+        self.buf.write_all(b"EXIT /B 0\n")?;
+        writeln!(self.buf, ":{}_end", label)?;
+        if self.exits {
+            let exit = self.exit_with(&format!("%{}%", EXIT));
+            writeln!(self.buf, "IF DEFINED {} {}", EXIT, exit)?;
+        }
+        if self.raises {
+            writeln!(self.buf, "IF DEFINED {} {}", ERROR, self.raise())?;
+        }
+        Ok(())
+    }
+
+    /// Iterates over the characters of a string one `GOTO` at a time
+    fn compile_for_chars(
+        &mut self,
+        label: &str,
+        item: &str,
+        string: Expr,
+        body: Expr,
+    ) -> io::Result<()> {
+        let name = self.var_name(item);
+        self.names.insert(name.clone(), item.to_owned());
+        let next = format!("{}_next", label);
+        let end = format!("{}_end", label);
+        self.compile_set(&format!("{}_rest", label), string)?;
+        writeln!(self.buf, ":{}", next)?;
+        writeln!(self.buf, "IF NOT DEFINED {}_rest GOTO :{}", label, end)?;
+        writeln!(self.buf, "SET \"{}=%{}_rest:~0,1%\"", name, label)?;
+        writeln!(self.buf, "SET \"{0}_rest=%{0}_rest:~1%\"", label)?;
+        self.stack.push(Jump::Label { next, end });
+        self.compile_expr(body)?;
+        if let Some(Jump::Label { next, end }) = self.stack.pop() {
+            writeln!(self.buf, "GOTO :{}", next)?;
+            writeln!(self.buf, ":{}", end)?;
+        }
+        Ok(())
+    }
+
+    /// Arrays are stored as a space separated list of quoted items, the same
+    /// form `FOR` and `%*` use
+    pub fn compile_array(&mut self, ast: Vec<Expr>) -> io::Result<()> {
+        let mut items = ast.into_iter().peekable();
+        while let Some(item) = items.next() {
+            let in_array = std::mem::replace(&mut self.in_array, true);
+            self.compile_quoted(item)?;
+            self.in_array = in_array;
+            if items.peek().is_some() {
+                self.buf.write_all(b" ")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile_func_def(&mut self, ast: Box<FuncDef>) -> io::Result<()> {
+        let label = func_label(self.module.as_deref(), &ast.name);
+        self.buf.write_all(b":")?;
+        self.buf.write_all(label.as_bytes())?;
+        let name = match self.module {
+            Some(ref module) => format!("{}::{}", module, ast.name),
+            None => ast.name.clone(),
+        };
+        self.names.insert(label, name);
+        self.buf.write_all(b" ")?;
+        if !ast.params.is_empty() {
+            self.compile_params_decl(ast.params)?;
+        }
+        self.buf.write_all(b"\n")?;
+        let handlers = std::mem::take(&mut self.handlers);
+        let trying = std::mem::replace(&mut self.trying, 0);
+        let in_subroutine = std::mem::replace(&mut self.in_subroutine, true);
+        self.compile_expr(ast.body)?;
+        self.handlers = handlers;
+        self.trying = trying;
+        self.in_subroutine = in_subroutine;
+        self.buf.write_all(b"\n")?;
+        self.buf.write_all(b"EXIT /B 0\n")?;
+        Ok(())
+    }
+
+    pub fn compile_variable(&mut self, ast: String) -> io::Result<()> {
+        let name = self.var_name(&ast);
+        self.read_var(&name)
+    }
+
+    /// Reads the cmd variable `name`, with `!name!` on delayed lines
+    fn read_var(&mut self, name: &str) -> io::Result<()> {
+        if self.delayed {
+            write!(self.buf, "!{}!", name)
+        } else {
+            write!(self.buf, "%{}%", name)
+        }
+    }
+
+    /// A fresh label prefix such as `__ski_while_3`. Labels are numbered per
+    /// compiler so identical input always produces identical output
+    fn new_label(&mut self, kind: &str) -> String {
+        let label = format!("__ski_{}_{}", kind, self.labels);
+        self.labels += 1;
+        label
+    }
+
+    /// The cmd variable that holds the ski variable `name`
+    fn var_name(&self, name: &str) -> String {
+        if self.exported.contains(name) {
+            name.to_owned()
+        } else {
+            format!("{}{}", VAR_PREFIX, mangle(name))
+        }
+    }
+
+    pub fn compile_params_decl(&mut self, mut ast: Vec<String>) -> io::Result<()> {
+        let p = ast.pop();
+        for x in ast {
+            self.buf.write_all(x.as_bytes())?;
+            self.buf.write_all(b", ")?;
+        }
+        let inside = p.unwrap();
+        self.buf.write_all(inside.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn compile_params(&mut self, mut ast: Vec<Expr>) -> io::Result<()> {
+        let p = match ast.pop() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        for x in ast {
+            self.compile_expr(x)?;
+            self.buf.write_all(b", ")?;
+        }
+        self.compile_expr(p)?;
+        Ok(())
+    }
+
+    pub fn compile_return(&mut self, mut ast: Box<Expr>) -> io::Result<()> {
+        self.check_finally("return", 0)?;
+        // `%~1` would be the argument of the body's own subroutine
+        if self.stack.iter().any(|j| matches!(j, Jump::Subroutine { .. })) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "`return` can't be used inside a `for` loop, use `break` instead",
+            ));
+        }
+        self.hoist(&mut ast)?;
+        self.compile_set("%~1", *ast)?;
+        self.buf.write_all(b"EXIT /B 0\n")?;
+        Ok(())
+    }
+
+    pub fn compile_break(&mut self) -> io::Result<()> {
+        self.check_finally("break", self.stack.len())?;
+        match self.stack.last().unwrap() {
+            Jump::Label { end, .. } => writeln!(self.buf, "GOTO :{}", end)?,
+            Jump::Subroutine { break_flag } => {
+                writeln!(self.buf, "SET \"{}=1\"", break_flag)?;
+                self.buf.write_all(b"EXIT /B 0\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile_continue(&mut self) -> io::Result<()> {
+        self.check_finally("continue", self.stack.len())?;
+        match self.stack.last().unwrap() {
+            Jump::Label { next, .. } => writeln!(self.buf, "GOTO :{}", next)?,
+            Jump::Subroutine { .. } => self.buf.write_all(b"EXIT /B 0\n")?,
+        }
+        Ok(())
+    }
+
+    pub fn compile_var_decl(&mut self, mut ast: Box<VariableDecl>) -> io::Result<()> {
+        self.hoist(&mut ast.value)?;
+        if ast.is_exported {
+            if !ast.name.is_ascii() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "exported names must be ASCII, as cmd can't read them back otherwise",
+                ));
+            }
+            self.exported.insert(ast.name.clone());
+            self.environment.insert(ast.name.clone());
+        }
+        match ast.value {
+            Expr::Array(_) => {
+                self.arrays.insert(ast.name.clone());
+            }
+            Expr::Variable(ref v) if self.arrays.contains(v) => {
+                self.arrays.insert(ast.name.clone());
+            }
+            Expr::FuncCall(ref c) if c.func_name == "args" => {
+                self.arrays.insert(ast.name.clone());
+            }
+            _ => {}
+        }
+        let name = self.var_name(&ast.name);
+        self.names.insert(name.clone(), ast.name.clone());
+        match ast.value {
+            Expr::FuncCall(call) if !is_value_builtin(&call.func_name) => {
+                self.compile_func_calll(call, Some(name))
+            }
+            value if ast.is_numeric => {
+                let in_quotes = std::mem::replace(&mut self.in_quotes, true);
+                write!(self.buf, "SET /A \"{}=", name)?;
+                self.compile_expr(value)?;
+                self.buf.write_all(b"\"\n")?;
+                self.in_quotes = in_quotes;
+                Ok(())
+            }
+            value => self.compile_set(&name, value),
+        }
+    }
+
+    pub fn compile_block(&mut self, ast: Vec<Expr>) -> io::Result<()> {
+        for x in ast {
+            self.compile_expr(x)?;
+        }
+        Ok(())
+    }
+
+    pub fn compile_while(&mut self, ast: Box<While>) -> io::Result<()> {
+        // The condition and body are kept outside of any parenthesised block,
+        // so each `GOTO` back to the check re-parses and re-expands them
+        let label = self.new_label("while");
+        let check = format!("{}_check", label);
+        let end = format!("{}_end", label);
+        let mut cond = ast.cond;
+        writeln!(self.buf, ":{}", check)?;
+        self.hoist(&mut cond)?;
+        self.jump_unless(cond, &end)?;
+        self.stack.push(Jump::Label { next: check, end });
+        self.compile_expr(ast.body)?;
+        if let Some(Jump::Label { next, end }) = self.stack.pop() {
+            writeln!(self.buf, "GOTO :{}", next)?;
+            writeln!(self.buf, ":{}", end)?;
+        }
+        Ok(())
+    }
+
+    /// Jumps to `target` if `cond` is false. cmd's `IF` has no `AND` or
+    /// `OR`, so `&&` and `||` are lowered to one `IF` per operand
+    fn jump_unless(&mut self, cond: Expr, target: &str) -> io::Result<()> {
+        match group_logical(cond) {
+            Expr::Binary(i) if is_and(&i.op) => {
+                let BinaryExpr { left, right, .. } = *i;
+                self.jump_unless(left, target)?;
+                self.jump_unless(right, target)
+            }
+            Expr::Binary(i) if is_or(&i.op) => {
+                let BinaryExpr { left, right, .. } = *i;
+                let ok = self.new_label("or");
+                self.jump_if(left, &ok)?;
+                self.jump_unless(right, target)?;
+                writeln!(self.buf, ":{}", ok)
+            }
+            Expr::Unary(i) if i.op == UnaryOpKind::LogicalNot => self.jump_if(i.child, target),
+            Expr::Paren(i) => self.jump_unless(*i, target),
+            cond => {
+                self.buf.write_all(b"IF NOT ")?;
+                self.compile_cond(cond)?;
+                writeln!(self.buf, " GOTO :{}", target)
+            }
+        }
+    }
+
+    /// Jumps to `target` if `cond` is true, like `jump_unless`
+    fn jump_if(&mut self, cond: Expr, target: &str) -> io::Result<()> {
+        match group_logical(cond) {
+            Expr::Binary(i) if is_and(&i.op) => {
+                let BinaryExpr { left, right, .. } = *i;
+                let skip = self.new_label("and");
+                self.jump_unless(left, &skip)?;
+                self.jump_if(right, target)?;
+                writeln!(self.buf, ":{}", skip)
+            }
+            Expr::Binary(i) if is_or(&i.op) => {
+                let BinaryExpr { left, right, .. } = *i;
+                self.jump_if(left, target)?;
+                self.jump_if(right, target)
+            }
+            Expr::Unary(i) if i.op == UnaryOpKind::LogicalNot => self.jump_unless(i.child, target),
+            Expr::Paren(i) => self.jump_if(*i, target),
+            cond => {
+                self.buf.write_all(b"IF ")?;
+                self.compile_cond(cond)?;
+                writeln!(self.buf, " GOTO :{}", target)
+            }
+        }
+    }
+
+    pub fn compile_loop(&mut self, ast: Box<Loop>) -> io::Result<()> {
+        let label = self.new_label("loop");
+        let start = format!("{}_start", label);
+        writeln!(self.buf, ":{}", start)?;
+        let end = format!("{}_end", label);
+        self.stack.push(Jump::Label { next: start, end });
+        self.compile_expr(ast.body)?;
+        if let Some(Jump::Label { next, end }) = self.stack.pop() {
+            writeln!(self.buf, "GOTO :{}", next)?;
+            writeln!(self.buf, ":{}", end)?;
+        }
+        Ok(())
+    } 
+
+    pub fn compile_binary_op_kind(&mut self, ast: BinaryOpKind) -> io::Result<()> {
+        self.buf.write_all(match ast {
+            BinaryOpKind::Add => b"+",
+            BinaryOpKind::Sub => b"-",
+            BinaryOpKind::Mul => b"*",
+            BinaryOpKind::Div => b"/",
+            BinaryOpKind::Assign => b"=",
+            BinaryOpKind::Eq => b" EQU ",
+            BinaryOpKind::Ne => b" NEQ ",
+            BinaryOpKind::Gt => b" GTR ",
+            BinaryOpKind::Lt => b" LSS ",
+            BinaryOpKind::GtEq => b" GEQ ",
+            BinaryOpKind::LtEq => b" LEQ ",
+            BinaryOpKind::Shr => b">>",
+            BinaryOpKind::Shl => b"<<",
+            BinaryOpKind::Xor => b"^",
+            BinaryOpKind::LogicalAnd => b"AND",
+            BinaryOpKind::LogicalOr => b"OR",
+            BinaryOpKind::BinaryAnd => b"&",
+            BinaryOpKind::BinaryOr => b"|",
+        })?;
+        Ok(())
+    }
+    pub fn compile_unary_op_kind(&mut self, ast: UnaryOpKind) -> io::Result<()> {
+        self.buf.write_all(match ast {
+            UnaryOpKind::Minus => b"-",
+            UnaryOpKind::LogicalNot => b"NOT ",
+            UnaryOpKind::BitwiseNot => b"~",
+        })?;
+        Ok(())
+    }
+}
+
+/// A piece of a command line
+#[derive(Debug)]
+enum CommandPart {
+    /// Text known when compiling
+    Text(String),
+    /// A variable whose value is only known when the script runs
+    Var(String),
+    /// A pipe or redirection, which cmd has to see unescaped
+    Operator(&'static str),
+}
+
+/// The name passed to `env`, `env_or` or `set_env`, which has to be a
+/// string literal
+fn env_name(func_name: &str, name: Option<Expr>) -> io::Result<String> {
+    match name {
+        Some(Expr::Str(name))
+            if !name.is_empty() && name.is_ascii() && !name.contains(&['%', '=', '"'][..]) =>
+        {
+            Ok(name)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "`{}` takes the variable's name as a string of ASCII characters other than `%`, `=` and `\"`",
+                func_name
+            ),
+        )),
+    }
+}
+
+/// The label of the function `name`, which is prefixed with the name of the
+/// module it is declared in, if that isn't the script
+fn func_label(module: Option<&str>, name: &str) -> String {
+    match module {
+        Some(module) => format!("{}.{}", mangle(module), mangle(name)),
+        None => mangle(name),
+    }
+}
+
+/// Adds the environment variables that `ast` sets to `names`
+fn find_environment(ast: &Expr, names: &mut BTreeSet<String>) -> io::Result<()> {
+    match ast {
+        Expr::FuncCall(c) if c.func_name == "set_env" => {
+            names.insert(env_name(&c.func_name, c.params.first().cloned())?);
+        }
+        Expr::VariableDecl(v) if v.is_exported => {
+            names.insert(v.name.clone());
+        }
+        _ => {}
+    }
+    for child in ast.children() {
+        find_environment(child, names)?;
+    }
+    Ok(())
+}
+
+/// Adds the `extern fn` declarations in `ast` to `externs`
+fn find_externs(ast: &Expr, externs: &mut HashMap<String, Extern>) {
+    if let Expr::Extern(e) = ast {
+        externs.insert(e.name.clone(), (**e).clone());
+    }
+    for child in ast.children() {
+        find_externs(child, externs);
+    }
+}
+
+/// The path of a batch file to `CALL`. A relative path is relative to the
+/// directory of the script rather than the current directory
+fn batch_path(path: &str) -> String {
+    let path = path.replace('/', "\\").replace('%', "%%");
+    if path.starts_with('\\') || path.get(1..2) == Some(":") {
+        path
+    } else {
+        format!("%~dp0{}", path)
+    }
+}
+
+/// Builtins that run a program, and are compiled to statements of their own
+fn is_command(name: &str) -> bool {
+    matches!(
+        name,
+        "run" | "capture" | "capture_lines" | "read_lines" | "list_dir"
+    )
+}
+
+/// Builtins that run a program and return its output as an array of lines
+fn returns_array(name: &str) -> bool {
+    matches!(name, "capture_lines" | "read_lines" | "list_dir")
+}
+
+/// Doubles the quotes in the cmd variable `name`, which holds an array item,
+/// so the item can be wrapped in quotes. Runs with delayed expansion
+fn quote_item(name: &str) -> String {
+    format!("IF DEFINED {0} SET \"{0}=!{0}:\"=\"\"!\"", name)
+}
+
+/// Functions that are compiled to a value in place, rather than being
+/// `CALL`ed for their result
+fn is_value_builtin(name: &str) -> bool {
+    matches!(name, "args" | "args_count" | "last_status" | "env")
+}
+
+/// A value that reads no variables, so it can be written without delayed
+/// expansion
+fn is_literal(ast: &Expr) -> bool {
+    match ast {
+        Expr::Int(_) | Expr::Str(_) | Expr::Bool(_) => true,
+        Expr::Array(items) | Expr::Interpolated(items) => items.iter().all(is_literal),
+        _ => false,
+    }
+}
+
+/// Checks that `ast` can be computed by `SET /A`, which has no comparisons
+/// or logical operators
+fn check_arithmetic(ast: &Expr) -> io::Result<()> {
+    match ast {
+        Expr::Binary(b) => match b.op {
+            BinaryOpKind::Add
+            | BinaryOpKind::Sub
+            | BinaryOpKind::Mul
+            | BinaryOpKind::Div
+            | BinaryOpKind::Shr
+            | BinaryOpKind::Shl
+            | BinaryOpKind::Xor
+            | BinaryOpKind::BinaryAnd
+            | BinaryOpKind::BinaryOr => {
+                check_arithmetic(&b.left)?;
+                check_arithmetic(&b.right)
+            }
+            _ => Err(error_at(b.pos.0, "only arithmetic can be computed inside a string")),
+        },
+        Expr::Unary(u) if u.op == UnaryOpKind::LogicalNot => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only arithmetic can be computed inside a string",
+        )),
+        Expr::Unary(u) => check_arithmetic(&u.child),
+        Expr::Paren(e) => check_arithmetic(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_and(op: &BinaryOpKind) -> bool {
+    *op == BinaryOpKind::LogicalAnd
+}
+
+fn is_or(op: &BinaryOpKind) -> bool {
+    *op == BinaryOpKind::LogicalOr
+}
+
+/// Regroups a condition around its `||` and `&&`, which bind looser than
+/// the comparisons between them. The parser nests every binary expression
+/// to the right, so `a == 1 && b == 2` arrives as `a == (1 && (b == 2))`.
+fn group_logical(cond: Expr) -> Expr {
+    fn flatten_owned(ast: Expr, operands: &mut Vec<Expr>, ops: &mut Vec<(BinaryOpKind, SourcePos)>) {
+        match ast {
+            Expr::Binary(b) => {
+                let BinaryExpr { left, op, right, pos } = *b;
+                flatten_owned(left, operands, ops);
+                ops.push((op, pos));
+                flatten_owned(right, operands, ops);
+            }
+            ast => operands.push(ast),
+        }
+    }
+
+    fn regroup(mut operands: Vec<Expr>, mut ops: Vec<(BinaryOpKind, SourcePos)>) -> Expr {
+        for split in &[BinaryOpKind::LogicalOr, BinaryOpKind::LogicalAnd] {
+            if let Some(i) = ops.iter().position(|(op, _)| op == split) {
+                let right_ops = ops.split_off(i + 1);
+                let (op, pos) = ops.pop().expect("found at `i`");
+                let right = operands.split_off(i + 1);
+                return Expr::Binary(Box::new(BinaryExpr {
+                    left: regroup(operands, ops),
+                    op,
+                    right: regroup(right, right_ops),
+                    pos,
+                }));
+            }
+        }
+        let mut right = operands.pop().expect("one more operand than operators");
+        while let (Some((op, pos)), Some(left)) = (ops.pop(), operands.pop()) {
+            right = Expr::Binary(Box::new(BinaryExpr { left, op, right, pos }));
+        }
+        right
+    }
+
+    let mut operands = Vec::new();
+    let mut ops = Vec::new();
+    flatten_owned(cond, &mut operands, &mut ops);
+    regroup(operands, ops)
+}
+
+/// Escapes text inside a double quoted argument the way programs built on
+/// the Microsoft C runtime split their command line back apart: `"` becomes
+/// `\"`, and backslashes are doubled where they come before a quote,
+/// including the closing quote if `is_end`
+fn escape_arg(arg: &str, is_end: bool) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                escaped.push_str(&"\\".repeat(backslashes * 2 + 1));
+                escaped.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                escaped.push_str(&"\\".repeat(backslashes));
+                escaped.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    let trailing = if is_end { backslashes * 2 } else { backslashes };
+    escaped.push_str(&"\\".repeat(trailing));
+    escaped
+}
+
+/// Whether `word` can be written on a command line without quotes
+fn is_plain_word(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:\\/".contains(c))
+}
+
+/// Whether `ast` or any expression inside it matches `f`
+fn contains(ast: &Expr, f: &dyn Fn(&Expr) -> bool) -> bool {
+    f(ast) || ast.children().into_iter().any(|child| contains(child, f))
+}
+
+/// Whether `ast` calls the function `name` anywhere
+fn calls(ast: &Expr, name: &str) -> bool {
+    contains(ast, &|e| matches!(e, Expr::FuncCall(c) if c.func_name == name))
+}
+
+/// Finds the `flags` declarations in `ast` and whether it calls `args()` or
+/// `args_count()`
+fn find_args<'a>(ast: &'a Expr, flags: &mut Vec<&'a Vec<Flag>>, uses_args: &mut bool) {
+    match ast {
+        Expr::Flags(f) => flags.push(f),
+        Expr::FuncCall(c) if c.func_name == "args" || c.func_name == "args_count" => {
+            *uses_args = true
+        }
+        _ => {}
+    }
+    for child in ast.children() {
+        find_args(child, flags, uses_args);
+    }
+}
+
+/// The value of an integer literal, including negated ones
+fn int_literal(ast: &Expr) -> Option<i64> {
+    match ast {
+        Expr::Int(i) => Some(*i),
+        Expr::Paren(e) => int_literal(e),
+        Expr::Unary(u) if u.op == UnaryOpKind::Minus => int_literal(&u.child).map(|i| -i),
+        _ => None,
+    }
+}
+
+fn overflow() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "integer overflow: cmd only has 32-bit signed integers",
+    )
+}
+
+/// Rejects integers that `SET /A` can't represent
+fn check_int(i: i64) -> io::Result<i64> {
+    if i < i64::from(i32::MIN) || i > i64::from(i32::MAX) {
+        return Err(overflow());
+    }
+    Ok(i)
+}
+
+/// Evaluates a binary expression made only of integer literals the way
+/// `SET /A` would evaluate the text it compiles to, or `None` if it isn't
+/// constant. Every intermediate value must fit in 32 bits.
+fn const_value(ast: &BinaryExpr) -> Option<io::Result<i64>> {
+    let mut operands = Vec::new();
+    let mut ops = Vec::new();
+    flatten(&ast.left, &mut operands, &mut ops);
+    ops.push(ast);
+    flatten(&ast.right, &mut operands, &mut ops);
+    if ops.iter().any(|op| precedence(&op.op).is_none()) {
+        return None;
+    }
+    let mut values = Vec::with_capacity(operands.len());
+    for operand in operands {
+        let value = match operand {
+            Expr::Int(i) => *i,
+            Expr::Unary(u) => match (&u.op, &u.child) {
+                (UnaryOpKind::Minus, Expr::Int(i)) => -*i,
+                (UnaryOpKind::BitwiseNot, Expr::Int(i)) => !*i,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        values.push(value);
+    }
+    let mut pos = 0;
+    Some(climb(&values, &ops, &mut pos, values[0], 0))
+}
+
+/// Collects the operands and operators of an expression in the order they are
+/// written. Parentheses aren't written to cmd, so they don't group here either.
+fn flatten<'a>(ast: &'a Expr, operands: &mut Vec<&'a Expr>, ops: &mut Vec<&'a BinaryExpr>) {
+    match ast {
+        Expr::Binary(b) => {
+            flatten(&b.left, operands, ops);
+            ops.push(b);
+            flatten(&b.right, operands, ops);
+        }
+        Expr::Paren(e) => flatten(e, operands, ops),
+        _ => operands.push(ast),
+    }
+}
+
+/// Binding strength of `SET /A` operators, `None` for operators it doesn't
+/// evaluate
+fn precedence(op: &BinaryOpKind) -> Option<u8> {
+    Some(match op {
+        BinaryOpKind::Mul | BinaryOpKind::Div => 5,
+        BinaryOpKind::Add | BinaryOpKind::Sub => 4,
+        BinaryOpKind::Shl | BinaryOpKind::Shr => 3,
+        BinaryOpKind::BinaryAnd => 2,
+        BinaryOpKind::Xor => 1,
+        BinaryOpKind::BinaryOr => 0,
+        _ => return None,
+    })
+}
+
+/// Precedence climbing over `values[pos..]`, starting from `lhs`
+fn climb(
+    values: &[i64],
+    ops: &[&BinaryExpr],
+    pos: &mut usize,
+    mut lhs: i64,
+    min: u8,
+) -> io::Result<i64> {
+    while *pos < ops.len() && precedence(&ops[*pos].op) >= Some(min) {
+        let op = ops[*pos];
+        let prec = precedence(&op.op);
+        let mut rhs = check_int(values[*pos + 1])?;
+        *pos += 1;
+        while *pos < ops.len() && precedence(&ops[*pos].op) > prec {
+            rhs = climb(values, ops, pos, rhs, prec.unwrap() + 1)?;
+        }
+        lhs = check_int(match op.op {
+            BinaryOpKind::Mul => lhs * rhs,
+            BinaryOpKind::Div if rhs == 0 => {
+                return Err(error_at(op.pos.0, "division by zero in a constant expression"))
+            }
+            BinaryOpKind::Div => lhs / rhs,
+            BinaryOpKind::Add => lhs + rhs,
+            BinaryOpKind::Sub => lhs - rhs,
+            BinaryOpKind::Shl if !(0..=31).contains(&rhs) => return Err(error_at(op.pos.0, overflow())),
+            BinaryOpKind::Shl => lhs << rhs,
+            BinaryOpKind::Shr => lhs >> rhs.clamp(0, 31),
+            BinaryOpKind::BinaryAnd => lhs & rhs,
+            BinaryOpKind::Xor => lhs ^ rhs,
+            _ => lhs | rhs,
+        })
+        .map_err(|e| error_at(op.pos.0, e))?;
+    }
+    Ok(lhs)
+}
+
+/// An error in the source at `pos`
+fn error_at(pos: Pos, message: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", pos, message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::module;
+    use crate::parser::Parser;
+
+    fn try_compile(src: &str) -> io::Result<String> {
+        let tokens = Lexer::new(src).lex().unwrap();
+        let module = Module {
+            name: "test".to_owned(),
+            path: PathBuf::from("test.ski"),
+            ast: Parser::new(tokens).parse().unwrap(),
+        };
+        let mut buf = Vec::new();
+        Compiler::new(&mut buf).compile(&[module])?;
+        Ok(String::from_utf8(buf).unwrap())
+    }
+
+    fn compile(src: &str) -> String {
+        try_compile(src).unwrap()
+    }
+
+    fn compile_file(path: &str) -> String {
+        let modules = module::load(Path::new(path)).unwrap();
+        let mut buf = Vec::new();
+        Compiler::new(&mut buf).compile(&modules).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn captured_lines_are_only_expanded_late() {
+        // `pwned.txt` has lines like `a&echo pwned`, which cmd would run if
+        // they were ever expanded with `%` outside of quotes
+        // `pwned.txt` also has a line with a lone `"`, which would end the
+        // quotes around it as an array item if it wasn't doubled
+        let out = compile_file("tests/fixtures/pwned.ski");
+        assert!(out.contains("findstr /n"));
+        assert!(out.contains("dir /b /a"));
+        for line in out.lines() {
+            // arrays are only expanded as lists of quoted items
+            if line.starts_with("FOR %%b IN (%ski_lines%) DO ") {
+                continue;
+            }
+            assert!(!line.contains("%ski_"), "{}", line);
+            assert!(!line.contains("%__ski_tmp_"), "{}", line);
+            assert!(!line.contains("_line%"), "{}", line);
+        }
+        let expected = "SETLOCAL EnableDelayedExpansion\n\
+                        SET \"ski_copy=!ski_lines!\"\n\
+                        FOR /F \"delims=\" %%c IN (\"\"!ski_copy!\"\") DO ENDLOCAL & SET \"ski_copy=%%~c\"\n";
+        assert!(out.contains(expected));
+        let quote = "IF DEFINED __ski_capture_1_line \
+                     SET \"__ski_capture_1_line=!__ski_capture_1_line:\"=\"\"!\"\n";
+        assert!(out.contains(quote));
+        assert!(out.contains("IF DEFINED ski_line SET \"ski_line=!ski_line:\"\"=\"!\"\n"));
+    }
+
+    #[test]
+    fn array_items_double_their_quotes() {
+        let out = compile(r#"{ let a = ["say \"hi\"", "b"]; }"#);
+        assert!(out.contains(r#"SET "ski_a="say ""hi""" "b""#));
+    }
+
+    #[test]
+    fn run_arguments_are_quoted() {
+        let out = compile(r#"{ run("prog", ["a&b", "say \"hi\"", "100%", "-v", ""]); }"#);
+        assert!(out.contains(r#"prog "a&b" "say \"hi\"" "100%%" -v """#));
+    }
+
+    #[test]
+    fn variables_are_prefixed_unless_exported() {
+        let out = compile("{ let path = 1; export let errorlevel = 2; print(path); }");
+        assert!(out.contains("SET \"ski_path=1\"\n"));
+        assert!(out.contains("SET \"errorlevel=2\"\n"));
+        assert!(out.contains("!ski_path!"));
+        assert!(!out.contains("SET path="));
+        assert!(try_compile("{ export let été = 1; }").is_err());
+    }
+
+    #[test]
+    fn printed_values_are_only_expanded_late() {
+        let out = compile_file("tests/fixtures/pwned.ski");
+        assert!(out.contains("SETLOCAL EnableDelayedExpansion\nSET \"ski_copy=!ski_lines!\"\n"));
+        assert!(out.contains("SETLOCAL EnableDelayedExpansion\nECHO(!ski_copy!\nENDLOCAL\n"));
+        assert!(out.contains("ECHO(names: !ski_names!\n"));
+        assert!(out.contains("ECHO(^> !ski_line!\n"));
+        for name in &["copy", "names", "line"] {
+            assert!(!out.contains(&format!("%ski_{}%", name)), "{}", out);
+        }
+    }
+
+    #[test]
+    fn special_characters_are_escaped() {
+        let out = compile(r#"{ print("50% & <more> | ^x!"); let s = "a&b"; }"#);
+        assert!(out.contains("ECHO(50%% ^& ^<more^> ^| ^^x!\n"));
+        assert!(out.contains("SET \"ski_s=a&b\"\n"));
+        let out = compile(r#"{ let s = "a"; print("{s} & ^x!"); let t = "{s}^!"; }"#);
+        assert!(out.contains("ECHO(!ski_s! ^& ^^^^x^^!\n"));
+        assert!(out.contains("SET \"ski_t=!ski_s!^^^!\"\n"));
+    }
+
+    #[test]
+    fn interpolated_calls_and_arithmetic_are_hoisted() {
+        let out = compile("{ fn f(a) { return a; } let a = 1; print(\"a {f(1)} b {a + 1}\"); }");
+        let expected = "CALL :f __ski_tmp_0 1\n\
+                        SET /A \"__ski_tmp_1=%ski_a%+1\"\n\
+                        SETLOCAL EnableDelayedExpansion\n\
+                        ECHO(a !__ski_tmp_0! b !__ski_tmp_1!\n";
+        assert!(out.contains(expected), "{}", out);
+        let out = compile("{ fn f(a) { return a; } let x = f(1); print(x); }");
+        assert!(out.contains("CALL :f ski_x 1\n"));
+        let err = try_compile("{ let a = 1; print(\"a {a == 2} b\"); }").unwrap_err();
+        assert!(err.to_string().starts_with("1:26: only arithmetic"), "{}", err);
+    }
+
+    #[test]
+    fn arguments_are_only_expanded_late() {
+        let out = compile("{ let a = args(); }");
+        assert!(!out.contains("\"%~1\"\""));
+        assert!(out.contains("SET \"__ski_args_0_arg=!__ski_args! \"!__ski_args_0_arg!\"\""));
+    }
+
+    #[test]
+    fn flags_are_compared_in_conditions() {
+        let out = compile("{ flags { verbose: bool, } if verbose { print(\"v\"); } }");
+        assert!(out.contains("IF NOT %ski_verbose% EQU 1 GOTO :__ski_if_1_end\n"));
+        let out = compile("{ flags { verbose: bool, } if !verbose { print(\"q\"); } }");
+        assert!(out.contains("IF %ski_verbose% EQU 1 GOTO :__ski_if_1_end\n"));
+    }
+
+    #[test]
+    fn output_commands_are_written_inline() {
+        let out = compile("{ for f in output(`git status`) { print(f); } }");
+        assert!(out.contains("IN (`\"git status | findstr /n \"^^\"\"`) DO"));
+    }
+
+    #[test]
+    fn internal_commands_are_not_quoted() {
+        let out = compile("{ let names = capture_lines(`dir /b | sort`); }");
+        assert!(out.contains("IN (`\"dir /b | sort\"`) DO"));
+        let out = compile(r#"{ run("dir", ["/b", "C:\\Program Files"]); }"#);
+        assert!(out.contains("dir /b \"C:\\Program Files\"\n"));
+    }
+
+    #[test]
+    fn for_dispatches_on_the_container() {
+        let out = compile("{ for i in range(0, 10) { print(i); } }");
+        assert!(out.contains("FOR /L %%b IN (0,1,9) DO"));
+        let out = compile("{ for i in range(10, 0, -2) { print(i); } }");
+        assert!(out.contains("FOR /L %%b IN (10,-2,1) DO"));
+        let out = compile("{ let n = 3; for i in range(0, n) { print(i); } }");
+        assert!(out.contains("SET /A __ski_for_0_last=%ski_n%-1\n"));
+        assert!(out.contains("FOR /L %%b IN (0,1,%__ski_for_0_last%) DO"));
+        let out = compile("{ for x in [\"a\", \"b\"] { print(x); } }");
+        assert!(out.contains("FOR %%b IN (\"a\" \"b\") DO"));
+        let out = compile("{ for c in \"ab\" { print(c); } }");
+        assert!(out.contains("SET \"ski_c=%__ski_for_0_rest:~0,1%\"\n"));
+        let out = compile("{ for f in glob(\"*.txt\") { print(f); } }");
+        assert!(out.contains("FOR %%b IN (\"*.txt\") DO"));
+    }
+
+    #[test]
+    fn lines_keep_empty_and_comment_lines() {
+        let out = compile("{ for l in lines(\"a b.txt\") { print(l); } }");
+        assert!(out.contains("FOR /F \"delims=\" %%b IN ('findstr /n \"^\" \"a b.txt\"') DO"));
+        let expected = ":__ski_for_0_body\n\
+                        SETLOCAL EnableDelayedExpansion\n\
+                        SET \"ski_l=!ski_l:*:=!\"\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn output_is_identical_across_runs() {
+        let src = "{ let a = 1; while a == 1 { loop { break; } } for i in [1, 2] { print(i); } }";
+        let first = compile(src);
+        assert!(first.contains(":__ski_while_0_check\n"));
+        assert_eq!(first, compile(src));
+    }
+
+    #[test]
+    fn return_ends_its_line() {
+        let out = compile("{ fn f(i) { return i; } }");
+        let expected = "SET \"%~1=!ski_i!\"\n\
+                        FOR /F \"delims=\" %%c IN (\"\"!%~1!\"\") DO ENDLOCAL & SET \"%~1=%%~c\"\n\
+                        EXIT /B 0\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn return_in_for_is_an_error() {
+        assert!(try_compile("{ fn f() { for i in [1] { return i; } } }").is_err());
+    }
+
+    #[test]
+    fn while_conditions_are_lowered() {
+        let out = compile("{ let a = 1; while a == 1 && !(a == 2) || a == 3 { print(a); } }");
+        assert!(!out.contains("AND") && !out.contains("OR"));
+        let expected = "IF NOT %ski_a% EQU 1 GOTO :__ski_and_2\n\
+                        IF NOT %ski_a% EQU 2 GOTO :__ski_or_1\n\
+                        :__ski_and_2\n\
+                        IF NOT %ski_a% EQU 3 GOTO :__ski_while_0_end\n\
+                        :__ski_or_1\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn if_branches_are_not_parenthesised() {
+        let src = "{ let a = 1; if a == 1 { while a == 2 { break; } \
+                   match a { 1 => print(\"one\"), _ => print(\"other\"), } } \
+                   else { try { run(`x`); } catch e { print(e); } } }";
+        let out = compile(src);
+        assert!(!out.lines().any(|l| l.ends_with(" (") || l.starts_with(')')), "{}", out);
+        let expected = "IF NOT %ski_a% EQU 1 GOTO :__ski_if_0_else\n\
+                        :__ski_while_1_check\n";
+        assert!(out.contains(expected));
+        let expected = ":__ski_match_2_end\n\
+                        GOTO :__ski_if_0_end\n\
+                        :__ski_if_0_else\n";
+        assert!(out.contains(expected));
+        assert!(out.contains(":__ski_try_3_finally\n:__ski_if_0_end\n"));
+    }
+
+    #[test]
+    fn if_conditions_are_lowered() {
+        let out = compile("{ let a = 1; if a == 1 && a != 2 { print(a); } }");
+        let expected = "IF NOT %ski_a% EQU 1 GOTO :__ski_if_0_end\n\
+                        IF NOT %ski_a% NEQ 2 GOTO :__ski_if_0_end\n\
+                        SETLOCAL EnableDelayedExpansion\n\
+                        ECHO(!ski_a!\n\
+                        ENDLOCAL\n\
+                        :__ski_if_0_end\n";
+        assert!(out.contains(expected));
+        let out = compile("{ let a = 1; if a == 1 || a == 2 { print(a); } }");
+        let expected = "IF %ski_a% EQU 1 GOTO :__ski_or_1\n\
+                        IF NOT %ski_a% EQU 2 GOTO :__ski_if_0_end\n\
+                        :__ski_or_1\n";
+        assert!(out.contains(expected));
+        assert!(!out.contains("AND") && !out.contains("OR"));
+    }
+
+    #[test]
+    fn match_is_lowered_to_jumps() {
+        let src = "{ let os = \"x\"; match /i os { \"win\" | \"windows\" => print(\"w\"), \
+                   \"linux\" => print(\"l\"), _ => print(\"?\"), } }";
+        let out = compile(src);
+        let expected = "IF /I \"%__ski_match_0_value%\"==\"win\" GOTO :__ski_match_0_arm_0\n\
+                        IF /I \"%__ski_match_0_value%\"==\"windows\" GOTO :__ski_match_0_arm_0\n\
+                        IF /I \"%__ski_match_0_value%\"==\"linux\" GOTO :__ski_match_0_arm_1\n\
+                        GOTO :__ski_match_0_default\n\
+                        :__ski_match_0_arm_0\n\
+                        ECHO(w\n\
+                        GOTO :__ski_match_0_end\n";
+        assert!(out.contains(expected));
+        assert!(out.contains(":__ski_match_0_default\nECHO(?\n:__ski_match_0_end\n"));
+        let out = compile("{ let os = \"x\"; match os { \"a\" => print(\"a\"), } }");
+        assert!(out.contains("IF \"%__ski_match_0_value%\"==\"a\" GOTO :__ski_match_0_arm_0\n"));
+        assert!(out.contains("GOTO :__ski_match_0_end\n:__ski_match_0_arm_0\n"));
+    }
+
+    #[test]
+    fn booleans_are_stored_as_numbers() {
+        let out = compile("{ let b = true; if false { print(\"no\"); } }");
+        assert!(out.contains("SET \"ski_b=1\"\n"));
+        assert!(out.contains("IF NOT 1 EQU 0 GOTO :__ski_if_0_end\n"));
+    }
+
+    #[test]
+    fn constant_errors_point_at_the_operator() {
+        let err = try_compile("{\n    let x = 1 << 40;\n}").unwrap_err();
+        assert!(err.to_string().starts_with("2:15: "), "{}", err);
+        let err = try_compile("{ let x = 4 / 0; }").unwrap_err();
+        assert!(err.to_string().starts_with("1:13: division by zero"), "{}", err);
+    }
+
+    #[test]
+    fn integers_must_fit_in_32_bits() {
+        assert!(compile("{ let x: num = -2147483648; }").contains("=-2147483648\""));
+        assert!(compile("{ let x: num = 0x7FFF_FFFF; }").contains("=2147483647\""));
+        assert!(try_compile("{ let x: num = 2147483648; }").is_err());
+        assert!(try_compile("{ let x: num = 0b1 << 31; }").is_err());
+    }
+
+    #[test]
+    fn exit_leaves_from_functions_and_loops() {
+        assert!(compile("{ exit(2); }").contains("SET \"__ski_exit=2\"\nEXIT /B 2\n"));
+        let out = compile("{ fn f() { exit(3); } f(); print(\"x\"); }");
+        assert!(out.contains("SET \"__ski_exit=3\"\nEXIT /B 3\n"));
+        assert!(out.contains("CALL :f \nIF DEFINED __ski_exit EXIT /B\n"));
+        let out = compile("{ for i in [1] { exit(4); } print(\"x\"); }");
+        assert!(out.contains("IF NOT DEFINED __ski_exit ("));
+        assert!(out.contains(":__ski_for_0_end\nIF DEFINED __ski_exit EXIT /B %__ski_exit%\n"));
+    }
+
+    #[test]
+    fn last_status_is_kept() {
+        let out = compile(r#"{ run("git", ["status"]); print(last_status()); }"#);
+        assert!(out.contains("SET \"__ski_status=%ERRORLEVEL%\"\n"));
+        assert!(out.contains("ECHO(!__ski_status!\n"));
+        let out = compile(r#"{ run("git", ["status"]); }"#);
+        assert!(!out.contains("__ski_status"));
+    }
+
+    #[test]
+    fn fail_fast_checks_every_command() {
+        let compile_fail_fast = |src: &str| {
+            let module = Module {
+                name: "test".to_owned(),
+                path: PathBuf::from("test.ski"),
+                ast: Parser::new(Lexer::new(src).lex().unwrap()).parse().unwrap(),
+            };
+            let mut buf = Vec::new();
+            Compiler::new(&mut buf).fail_fast(true).compile(&[module]).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let out = compile_fail_fast(r#"{ run("git", ["status"]); }"#);
+        let expected = "SET \"__ski_status=%ERRORLEVEL%\"\n\
+                        IF %__ski_status% NEQ 0 (SET \"__ski_error=git exited with code %__ski_status%\" \
+                        & GOTO :__ski_uncaught)\n";
+        assert!(out.contains(expected));
+        assert!(out.contains(":__ski_uncaught\nECHO(%__ski_error% 1>&2\nEXIT /B %__ski_status%\n"));
+        // a status that is kept is the script's to check
+        let out = compile_fail_fast(r#"{ let code = run("git", ["status"]); }"#);
+        assert!(!out.contains("NEQ 0"));
+    }
+
+    #[test]
+    fn try_jumps_to_catch_and_finally() {
+        let out = compile(
+            r#"{ try { run("git", ["status"]); } catch e { print(e); } finally { print("done"); } }"#,
+        );
+        let expected = "IF %__ski_status% NEQ 0 (SET \"__ski_error=git exited with code %__ski_status%\" \
+                        & GOTO :__ski_try_0_catch)\n\
+                        GOTO :__ski_try_0_finally\n\
+                        :__ski_try_0_catch\n\
+                        SET \"ski_e=%__ski_error%\"\n\
+                        SET \"__ski_error=\"\n";
+        assert!(out.contains(expected));
+        let expected = ":__ski_try_0_finally\n\
+                        SET \"__ski_try_0_status=%__ski_status%\"\n\
+                        ECHO(done\n\
+                        IF DEFINED __ski_error SET \"__ski_status=%__ski_try_0_status%\"\n\
+                        IF DEFINED __ski_error GOTO :__ski_uncaught\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn errors_from_functions_reach_the_finally_block() {
+        let out = compile(r#"{ fn f() { throw("bad"); } try { f(); } finally { print("done"); } }"#);
+        assert!(out.contains("SET \"__ski_error=bad\"\nSET /A __ski_status=1\nEXIT /B %__ski_status%\n"));
+        assert!(out.contains("CALL :f \nIF DEFINED __ski_error GOTO :__ski_try_0_finally\n"));
+    }
+
+    #[test]
+    fn try_inside_a_loop_stays_in_the_body() {
+        let out = compile(r#"{ for i in [1, 2] { try { throw("bad"); } catch e { print(e); } } }"#);
+        let body = out.find("\n:__ski_for_0_body\n").unwrap();
+        let end = out.find("\n:__ski_for_0_end\n").unwrap();
+        // the try's labels are inside the called body, so its GOTOs don't
+        // leave the loop's subroutine
+        for label in &[":__ski_try_1_catch\n", ":__ski_try_1_finally\n"] {
+            let at = out.find(label).unwrap();
+            assert!(body < at && at < end);
+        }
+        assert!(out.contains("IF NOT DEFINED __ski_for_0_break IF NOT DEFINED __ski_error ("));
+        assert!(out.contains(":__ski_for_0_end\nIF DEFINED __ski_error GOTO :__ski_uncaught\n"));
+    }
+
+    #[test]
+    fn env_reads_and_sets_environment_variables() {
+        let out = compile(r#"{ set_env("BUILD_DIR", "out\\{env("USERNAME")}"); print(env("PATH")); }"#);
+        let expected = "SETLOCAL EnableDelayedExpansion\n\
+                        SET \"BUILD_DIR=out\\!USERNAME!\"\n\
+                        FOR /F \"delims=\" %%c IN (\"\"!BUILD_DIR!\"\") DO ENDLOCAL & SET \"BUILD_DIR=%%~c\"\n\
+                        SETLOCAL EnableDelayedExpansion\n\
+                        ECHO(!PATH!\n";
+        assert!(out.contains(expected));
+        assert!(try_compile(r#"{ set_env("A=B", "x"); }"#).is_err());
+        assert!(try_compile(r#"{ let name = "A"; set_env(name, "x"); }"#).is_err());
+    }
+
+    #[test]
+    fn set_environment_variables_outlive_the_script() {
+        let out = compile(r#"{ set_env("A", "1"); export let b = 2; if true { exit(3); } }"#);
+        assert!(out.contains("SET \"__ski_exit=3\"\nGOTO :__ski_end\n"));
+        let expected = ":__ski_end\n\
+                        ENDLOCAL & SET \"A=%A%\" & SET \"b=%b%\" & EXIT /B %__ski_exit%\n";
+        assert!(out.contains(expected));
+        let out = compile(r#"{ print("x"); }"#);
+        assert!(!out.contains("ENDLOCAL & SET"));
+    }
+
+    #[test]
+    fn extern_calls_are_checked_against_the_declaration() {
+        let decl = r#"extern fn build(target, mode) from "build.bat";"#;
+        let err = try_compile(&format!("{{ {} build(\"a\"); }}", decl)).unwrap_err();
+        assert_eq!(err.to_string(), "wrong number of arguments to `build`: expected 2, got 1");
+        assert!(try_compile(&format!("{{ {} build(\"a\", \"b\", \"c\"); }}", decl)).is_err());
+        let err = try_compile(&format!("{{ {} build([\"a\"], \"b\"); }}", decl)).unwrap_err();
+        assert!(err.to_string().contains("can't be passed an array"));
+        let out = compile(&format!("{{ {} build(\"a b\", \"c\"); }}", decl));
+        assert!(out.contains("CALL \"%~dp0build.bat\" \"a b\" c\n"));
+    }
+
+    #[test]
+    fn extern_paths_are_relative_to_the_script() {
+        assert_eq!(batch_path("legacy/build.bat"), r"%~dp0legacy\build.bat");
+        assert_eq!(batch_path(r"C:\tools\build.bat"), r"C:\tools\build.bat");
+        assert_eq!(batch_path(r"\\server\share\build.bat"), r"\\server\share\build.bat");
+        assert_eq!(batch_path("100%.bat"), "%~dp0100%%.bat");
+        let out = compile(
+            r#"{ extern fn version(dir) from "legacy/tools.bat" :get_version returns VERSION;
+                 let v = version("src"); }"#,
+        );
+        let expected = "SET \"VERSION=\"\n\
+                        CALL \"%~dp0legacy\\tools.bat\" :get_version src\n\
+                        SET \"__ski_tmp_0=%VERSION%\"\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn spawn_runs_the_script_again_at_a_label() {
+        let out = compile(r#"{ let job = spawn { run("git", ["fetch"]); }; spawn { print("bg"); } }"#);
+        assert!(out.starts_with(
+            "@echo off\nREM AUTO-GENERATED FILE. DO NOT MODIFY.\n\
+             REM This file was automatically generated by the ski compiler.\n\
+             SETLOCAL\nIF \"%~1\"==\":__ski_spawned\" (CALL %* & EXIT /B)\n"
+        ));
+        let expected = "SET \"__ski_tmp_0=%TEMP%\\__ski_spawn_1_%RANDOM%%RANDOM%\"\n\
+                        START \"\" /B cmd /D /C CALL \"%~f0\" :__ski_spawned __ski_spawn_1 \"%__ski_tmp_0%\"\n\
+                        GOTO :__ski_spawn_1_end\n\
+                        :__ski_spawn_1\n\
+                        SET \"__ski_status=0\"\n\
+                        git fetch\n";
+        assert!(out.contains(expected));
+        // a spawn without a handle doesn't write its exit code anywhere
+        assert!(out.contains("START \"\" /B cmd /D /C CALL \"%~f0\" :__ski_spawned __ski_spawn_2\n"));
+        let expected = ":__ski_spawned\n\
+                        CALL :%~1\n\
+                        IF \"%~2\"==\"\" EXIT /B\n\
+                        >\"%~2.tmp\" ECHO(%ERRORLEVEL%\n\
+                        MOVE /Y \"%~2.tmp\" \"%~2\" >NUL\n";
+        assert!(out.contains(expected));
+    }
+
+    #[test]
+    fn wait_polls_for_the_exit_code() {
+        let out = compile("{ let job = spawn { print(\"x\"); }; let code = wait(job); }");
+        let expected = ":__ski_wait_3\n\
+                        IF EXIST \"%__ski_wait_3_file%\" GOTO :__ski_wait_3_done\n\
+                        PING -n 2 127.0.0.1 >NUL\n\
+                        GOTO :__ski_wait_3\n\
+                        :__ski_wait_3_done\n\
+                        SET /P \"__ski_wait_3_code=\" <\"%__ski_wait_3_file%\"\n\
+                        DEL \"%__ski_wait_3_file%\"\n\
+                        SET \"__ski_status=%__ski_wait_3_code%\"\n";
+        assert!(out.contains(expected));
+        assert!(try_compile("{ wait(); }").is_err());
+        assert!(try_compile("{ let a = 1; wait(a, a); }").is_err());
+    }
+
+    #[test]
+    fn escape_arg_doubles_backslashes_before_quotes() {
+        assert_eq!(escape_arg(r"C:\dir\file", true), r"C:\dir\file");
+        assert_eq!(escape_arg(r#"say "hi""#, true), r#"say \"hi\""#);
+        assert_eq!(escape_arg(r#"a\"b"#, true), r#"a\\\"b"#);
+        assert_eq!(escape_arg(r"C:\dir\", true), r"C:\dir\\");
+        assert_eq!(escape_arg(r"C:\dir\", false), r"C:\dir\");
+    }
 }
@@ -1,22 +1,38 @@
 use std::fmt;
 
+use crate::lexer::Pos;
+
 /// Container for errors that can occur during lexing
 #[derive(Debug)]
 pub enum LexingError {
-    InvalidIntegerLiteralCharacter(u32),
-    RegexError(regex::Error, u32),
-    ParseIntError(std::num::ParseIntError, u32),
+    /// An integer literal followed by a letter or digit it can't contain,
+    /// like `0b12`, with the position of the literal
+    InvalidIntegerLiteralCharacter(Pos),
+    /// An integer literal too large to store, with its position
+    ParseIntError(std::num::ParseIntError, Pos),
+    /// A string literal that reaches the end of the file, with the position
+    /// of its opening quote
+    UnterminatedString(Pos),
+    InvalidEscape(char, Pos),
+    /// A block comment that reaches the end of the file, with the position of
+    /// its opening `/*`
+    UnterminatedComment(Pos),
+    /// A character that can't start a token, like `@`
+    InvalidCharacter(char, Pos),
 }
 
-impl std::convert::From<regex::Error> for LexingError {
-    fn from(error: regex::Error) -> Self {
-        LexingError::RegexError(error, line!())
-    }
-}
-
-impl std::convert::From<std::num::ParseIntError> for LexingError {
-    fn from(error: std::num::ParseIntError) -> Self {
-        LexingError::ParseIntError(error, line!())
+impl fmt::Display for LexingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexingError::InvalidIntegerLiteralCharacter(pos) => {
+                write!(f, "{}: invalid character in integer literal", pos)
+            }
+            LexingError::ParseIntError(e, pos) => write!(f, "{}: {}", pos, e),
+            LexingError::UnterminatedString(pos) => write!(f, "{}: unterminated string", pos),
+            LexingError::InvalidEscape(c, pos) => write!(f, "{}: invalid escape `\\{}`", pos, c),
+            LexingError::UnterminatedComment(pos) => write!(f, "{}: unterminated block comment", pos),
+            LexingError::InvalidCharacter(c, pos) => write!(f, "{}: unexpected character `{}`", pos, c),
+        }
     }
 }
 
@@ -31,3 +47,45 @@ impl std::convert::From<&'static str> for ParseError {
         ParseError::Error(error, line!())
     }
 }
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Eof => f.write_str("unexpected end of file"),
+            // the line is the parser's own, which tells apart errors that
+            // share a message
+            ParseError::Error(message, line) => write!(f, "{} (parser.rs:{})", message, line),
+        }
+    }
+}
+
+/// Container for errors that can occur while formatting
+#[derive(Debug)]
+pub enum FormatError {
+    Lexing(LexingError),
+    Parse(ParseError),
+    /// The formatted source no longer parses to the same program
+    ChangedProgram,
+}
+
+impl std::convert::From<LexingError> for FormatError {
+    fn from(error: LexingError) -> Self {
+        FormatError::Lexing(error)
+    }
+}
+
+impl std::convert::From<ParseError> for FormatError {
+    fn from(error: ParseError) -> Self {
+        FormatError::Parse(error)
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Lexing(e) => e.fmt(f),
+            FormatError::Parse(e) => e.fmt(f),
+            FormatError::ChangedProgram => f.write_str("formatting would change the program"),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::errors::FormatError;
+use crate::lexer::{Keyword, Lexer, Literal, Symbol, Token, TokenKind, Trivia, TriviaKind};
+use crate::parser::Parser;
+
+const INDENT: &str = "    ";
+
+/// Rewrites ski source in the canonical style, keeping its comments.
+///
+/// Only the whitespace between tokens changes, and the result has to parse
+/// to the same program as `src` or formatting fails.
+pub fn format(src: &str) -> Result<String, FormatError> {
+    let tokens = Lexer::lossless(src).lex()?;
+    let formatted = Formatter::new().format(&tokens);
+    // a file of only comments has no program to compare
+    if tokens.len() > 1 {
+        check_unchanged(src, &formatted)?;
+    }
+    Ok(formatted)
+}
+
+/// Makes sure `formatted` parses to the same program as `src`
+fn check_unchanged(src: &str, formatted: &str) -> Result<(), FormatError> {
+    let before = Parser::new(Lexer::new(src).lex()?).parse()?;
+    let after = Parser::new(Lexer::new(formatted).lex()?).parse()?;
+    if before != after {
+        return Err(FormatError::ChangedProgram);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Formatter {
+    out: String,
+    /// Brackets that have been opened and not yet closed
+    open: Vec<Symbol>,
+    /// Whether the next token or comment has to start a new line
+    newline: bool,
+    /// Whether the last token binds to the next one without a space, like a
+    /// unary `-`
+    tight: bool,
+    /// Whether the tokens are between the braces of `use module::{a, b}`,
+    /// which stay on one line
+    in_use_list: bool,
+}
+
+impl Formatter {
+    fn new() -> Formatter {
+        Formatter {
+            out: String::new(),
+            open: Vec::new(),
+            newline: false,
+            tight: false,
+            in_use_list: false,
+        }
+    }
+
+    fn format(mut self, tokens: &[Token]) -> String {
+        let mut prev: Option<&TokenKind> = None;
+        for token in tokens {
+            let source = match token.source {
+                Some(ref s) => s,
+                None => unreachable!("tokens come from a lossless lexer"),
+            };
+            // newlines in the source since the last token or comment
+            let mut newlines = 0;
+            for trivia in &source.leading_trivia {
+                if trivia.kind == TriviaKind::Whitespace {
+                    newlines += trivia.text.matches('\n').count();
+                } else {
+                    self.comment(trivia, newlines, prev);
+                    newlines = 0;
+                }
+            }
+            let kind = &token.token_kind;
+            if *kind == TokenKind::Eof {
+                break;
+            }
+            if let TokenKind::Symbol(Symbol::CloseBracket)
+            | TokenKind::Symbol(Symbol::CloseParen)
+            | TokenKind::Symbol(Symbol::CloseSquareBracket) = kind
+            {
+                self.open.pop();
+            }
+            if self.newline || self.breaks_line(prev, kind, newlines) {
+                let blank = newlines > 1
+                    && prev != Some(&TokenKind::Symbol(Symbol::OpenBracket))
+                    && *kind != TokenKind::Symbol(Symbol::CloseBracket);
+                self.line_break(blank);
+            } else if self.spaced(prev, kind) {
+                self.out.push(' ');
+            }
+            self.write(&source.text);
+            self.newline = false;
+            self.tight = match kind {
+                TokenKind::Symbol(Symbol::LogicalNot) | TokenKind::Symbol(Symbol::BitwiseNot) => {
+                    true
+                }
+                TokenKind::Symbol(Symbol::Sub) => !prev.is_some_and(ends_operand),
+                // the `/` of `match /i`
+                TokenKind::Symbol(Symbol::Div) => prev == Some(&TokenKind::Keyword(Keyword::Match)),
+                // the `:` of `extern fn f() from "file.bat" :label`
+                TokenKind::Symbol(Symbol::Colon) => {
+                    matches!(prev, Some(TokenKind::Literal(Literal::Str(_))))
+                }
+                _ => false,
+            };
+            match kind {
+                TokenKind::Symbol(Symbol::OpenBracket) => {
+                    self.in_use_list = prev == Some(&TokenKind::Symbol(Symbol::PathSep));
+                }
+                TokenKind::Symbol(Symbol::CloseBracket) => self.in_use_list = false,
+                _ => {}
+            }
+            if let TokenKind::Symbol(s @ Symbol::OpenBracket)
+            | TokenKind::Symbol(s @ Symbol::OpenParen)
+            | TokenKind::Symbol(s @ Symbol::OpenSquareBracket) = kind
+            {
+                self.open.push(s.clone());
+            }
+            prev = Some(kind);
+        }
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+
+    /// Writes a comment that came `newlines` lines after the previous token,
+    /// either at the end of that token's line or on a line of its own
+    fn comment(&mut self, trivia: &Trivia, newlines: usize, prev: Option<&TokenKind>) {
+        if !self.out.is_empty() {
+            if newlines == 0 && !self.newline {
+                self.out.push(' ');
+            } else {
+                let blank = newlines > 1 && prev != Some(&TokenKind::Symbol(Symbol::OpenBracket));
+                self.line_break(blank);
+            }
+        }
+        self.write(&trivia.text);
+        self.newline = trivia.kind == TriviaKind::LineComment || newlines > 0;
+        self.tight = false;
+    }
+
+    /// Whether `next` starts a new line. Blocks and match arms always put one
+    /// statement or arm per line, and a line break in the source is kept
+    /// where it separates two statements.
+    fn breaks_line(&self, prev: Option<&TokenKind>, next: &TokenKind, newlines: usize) -> bool {
+        let prev = match prev {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.in_use_list {
+            return false;
+        }
+        let in_block = match self.open.last() {
+            Some(Symbol::OpenBracket) | None => true,
+            Some(_) => false,
+        };
+        match (prev, next) {
+            (TokenKind::Symbol(Symbol::OpenBracket), TokenKind::Symbol(Symbol::CloseBracket)) => {
+                false
+            }
+            (TokenKind::Symbol(Symbol::OpenBracket), _)
+            | (_, TokenKind::Symbol(Symbol::CloseBracket)) => true,
+            (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Keyword(Keyword::Else))
+            | (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Keyword(Keyword::Catch))
+            | (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Keyword(Keyword::Finally))
+            | (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Symbol(Symbol::SemiColon))
+            | (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Symbol(Symbol::Comma))
+            | (TokenKind::Symbol(Symbol::CloseBracket), TokenKind::Symbol(Symbol::CloseParen)) => {
+                false
+            }
+            (TokenKind::Symbol(Symbol::CloseBracket), _) => true,
+            (TokenKind::Symbol(Symbol::SemiColon), _) | (TokenKind::Symbol(Symbol::Comma), _) => {
+                in_block
+            }
+            _ => newlines > 0 && in_block && ends_statement(prev) && starts_statement(next),
+        }
+    }
+
+    /// Whether a space goes between two tokens on the same line
+    fn spaced(&self, prev: Option<&TokenKind>, next: &TokenKind) -> bool {
+        let prev = match prev {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.tight {
+            return false;
+        }
+        if self.in_use_list
+            && matches!(
+                (prev, next),
+                (TokenKind::Symbol(Symbol::OpenBracket), _)
+                    | (_, TokenKind::Symbol(Symbol::CloseBracket))
+            )
+        {
+            return false;
+        }
+        match (prev, next) {
+            (TokenKind::Literal(Literal::Str(_)), TokenKind::Symbol(Symbol::Colon)) => true,
+            (_, TokenKind::Symbol(Symbol::Comma))
+            | (_, TokenKind::Symbol(Symbol::SemiColon))
+            | (_, TokenKind::Symbol(Symbol::Colon))
+            | (_, TokenKind::Symbol(Symbol::PathSep))
+            | (TokenKind::Symbol(Symbol::PathSep), _)
+            | (_, TokenKind::Symbol(Symbol::CloseParen))
+            | (_, TokenKind::Symbol(Symbol::CloseSquareBracket))
+            | (TokenKind::Symbol(Symbol::OpenBracket), TokenKind::Symbol(Symbol::CloseBracket))
+            | (TokenKind::Symbol(Symbol::OpenParen), _)
+            | (TokenKind::Symbol(Symbol::OpenSquareBracket), _) => false,
+            // calls and indexing
+            (TokenKind::Identifier(_), TokenKind::Symbol(Symbol::OpenParen))
+            | (TokenKind::Identifier(_), TokenKind::Symbol(Symbol::OpenSquareBracket))
+            | (
+                TokenKind::Symbol(Symbol::CloseParen),
+                TokenKind::Symbol(Symbol::OpenSquareBracket),
+            )
+            | (
+                TokenKind::Symbol(Symbol::CloseSquareBracket),
+                TokenKind::Symbol(Symbol::OpenSquareBracket),
+            ) => false,
+            _ => true,
+        }
+    }
+
+    fn line_break(&mut self, blank: bool) {
+        self.out.push('\n');
+        if blank {
+            self.out.push('\n');
+        }
+    }
+
+    /// Writes text, indenting it if it starts a line
+    fn write(&mut self, text: &str) {
+        if self.out.ends_with('\n') {
+            for _ in 0..self.open.len() {
+                self.out.push_str(INDENT);
+            }
+        }
+        self.out.push_str(text);
+    }
+}
+
+/// Whether a token can be the end of an operand, making a following `-` binary
+fn ends_operand(t: &TokenKind) -> bool {
+    matches!(
+        t,
+        TokenKind::Identifier(_)
+            | TokenKind::Literal(_)
+            | TokenKind::Symbol(Symbol::CloseParen)
+            | TokenKind::Symbol(Symbol::CloseSquareBracket)
+    )
+}
+
+fn ends_statement(t: &TokenKind) -> bool {
+    match t {
+        TokenKind::Keyword(Keyword::Break)
+        | TokenKind::Keyword(Keyword::Continue)
+        | TokenKind::Keyword(Keyword::Return) => true,
+        _ => ends_operand(t),
+    }
+}
+
+fn starts_statement(t: &TokenKind) -> bool {
+    match t {
+        TokenKind::Identifier(_) | TokenKind::Literal(Literal::Command(_)) => true,
+        TokenKind::Keyword(k) => !matches!(
+            k,
+            Keyword::Else | Keyword::In | Keyword::Num | Keyword::Catch | Keyword::Finally
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCES: &[&str] = &[
+        "{ let x = 1 + 2; print(\"{x}\"); }",
+        "// ski: fail_fast\n{\n// a comment\nfn f(a, b) { return a + b; }\n\n\nlet y = f(1, -2); /* block */ print(y);\n}\n",
+        "{ for i in range(0, 10) { if i == 2 { continue; } else { print(i); } } }",
+        "{ import \"util.ski\"; use util :: {a, b}; extern fn v(d) from \"t.bat\" :get returns V; }",
+        "{ match x { \"a\" | \"b\" => print(1), _ => { print(2); } } }",
+        "{ flags { verbose: bool, out: path = \"x\", } run(`git log | findstr a`); }",
+        "// only a comment\n",
+    ];
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for src in SOURCES {
+            let once = format(src).unwrap();
+            assert_eq!(format(&once).unwrap(), once, "{}", src);
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_comments() {
+        let formatted = format(SOURCES[1]).unwrap();
+        for comment in &["// ski: fail_fast", "// a comment", "/* block */"] {
+            assert!(formatted.contains(comment), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn changed_programs_are_rejected() {
+        assert!(check_unchanged("{ let x = 1 + 2; }", "{\n    let x = 1 + 2;\n}\n").is_ok());
+        assert!(matches!(
+            check_unchanged("{ let x = 1; }", "{ let x = 2; }"),
+            Err(FormatError::ChangedProgram)
+        ));
+    }
+}
@@ -1,26 +1,43 @@
-use regex;
+use std::fmt;
 
 use crate::errors::LexingError;
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum Literal {
     Str(String),
+    /// A string containing `{expr}` interpolations
+    Interpolated(Vec<StrPart>),
+    /// A command line between backticks, which may contain `{expr}`
+    /// interpolations
+    Command(Vec<StrPart>),
     Int(u64),
     Bool(bool),
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
-enum LiteralKind {
-    Str(QuoteKind),
-    Int,
-    None,
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub enum StrPart {
+    Text(String),
+    Code(Vec<Token>),
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+impl Literal {
+    fn string(parts: &mut Vec<StrPart>, text: &str) -> Literal {
+        if parts.is_empty() {
+            return Literal::Str(text.to_owned());
+        }
+        if !text.is_empty() {
+            parts.push(StrPart::Text(text.to_owned()));
+        }
+        Literal::Interpolated(std::mem::take(parts))
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum Keyword {
     Function,
     Let,
     Const,
+    Export,
     For,
     In,
     While,
@@ -31,16 +48,29 @@ pub enum Keyword {
     Num,
     Continue,
     Break,
+    Match,
+    Flags,
+    Try,
+    Catch,
+    Finally,
+    Import,
+    Use,
+    Extern,
+    Spawn,
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum Symbol {
     OpenBracket,
     CloseBracket,
     OpenParen,
     CloseParen,
+    OpenSquareBracket,
+    CloseSquareBracket,
     SemiColon,
     Colon,
+    /// `::`, between a module and one of its functions
+    PathSep,
     Eq,
     Assign,
     Ne,
@@ -67,11 +97,12 @@ pub enum Symbol {
     BinaryAnd,
     BinaryOr,
     Comma,
+    FatArrow,
     LogicalNot,
     BitwiseNot,
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum TokenKind {
     Identifier(String),
     Literal(Literal),
@@ -80,6 +111,9 @@ pub enum TokenKind {
     Eof,
 }
 
+/// A place in the source. Columns and spans count Unicode scalar values, so
+/// a precomposed `é` is one column, but an `e` followed by a combining accent
+/// is two.
 #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone)]
 pub struct Pos {
     row: u32,
@@ -95,11 +129,18 @@ impl Pos {
             span: 0,
         }
     }
+
+    /// The start of line `row`, for things found outside the token stream
+    /// like comment pragmas
+    pub fn line(row: u32) -> Pos {
+        Pos { row, ..Pos::new() }
+    }
 }
 
-#[derive(Debug)]
-pub struct Lexer {
-    pos: Pos,
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.row, self.col)
+    }
 }
 
 impl TokenKind {
@@ -108,6 +149,7 @@ impl TokenKind {
             "fn" => TokenKind::Keyword(Keyword::Function),
             "let" => TokenKind::Keyword(Keyword::Let),
             "const" => TokenKind::Keyword(Keyword::Const),
+            "export" => TokenKind::Keyword(Keyword::Export),
             "for" => TokenKind::Keyword(Keyword::For),
             "while" => TokenKind::Keyword(Keyword::While),
             "loop" => TokenKind::Keyword(Keyword::Loop),
@@ -118,10 +160,21 @@ impl TokenKind {
             "in" => TokenKind::Keyword(Keyword::In),
             "continue" => TokenKind::Keyword(Keyword::Continue),
             "break" => TokenKind::Keyword(Keyword::Break),
+            "match" => TokenKind::Keyword(Keyword::Match),
+            "flags" => TokenKind::Keyword(Keyword::Flags),
+            "try" => TokenKind::Keyword(Keyword::Try),
+            "catch" => TokenKind::Keyword(Keyword::Catch),
+            "finally" => TokenKind::Keyword(Keyword::Finally),
+            "import" => TokenKind::Keyword(Keyword::Import),
+            "use" => TokenKind::Keyword(Keyword::Use),
+            "extern" => TokenKind::Keyword(Keyword::Extern),
+            "spawn" => TokenKind::Keyword(Keyword::Spawn),
             "{" => TokenKind::Symbol(Symbol::OpenBracket),
             "}" => TokenKind::Symbol(Symbol::CloseBracket),
             "(" => TokenKind::Symbol(Symbol::OpenParen),
             ")" => TokenKind::Symbol(Symbol::CloseParen),
+            "[" => TokenKind::Symbol(Symbol::OpenSquareBracket),
+            "]" => TokenKind::Symbol(Symbol::CloseSquareBracket),
             "=" => TokenKind::Symbol(Symbol::Assign),
             "==" => TokenKind::Symbol(Symbol::Eq),
             "!=" => TokenKind::Symbol(Symbol::Ne),
@@ -129,7 +182,9 @@ impl TokenKind {
             "~" => TokenKind::Symbol(Symbol::BitwiseNot),
             ";" => TokenKind::Symbol(Symbol::SemiColon),
             ":" => TokenKind::Symbol(Symbol::Colon),
+            "::" => TokenKind::Symbol(Symbol::PathSep),
             "," => TokenKind::Symbol(Symbol::Comma),
+            "=>" => TokenKind::Symbol(Symbol::FatArrow),
             "'" => TokenKind::Symbol(Symbol::SingleQuote),
             "\"" => TokenKind::Symbol(Symbol::DoubleQuote),
             "+" => TokenKind::Symbol(Symbol::Add),
@@ -173,323 +228,537 @@ impl QuoteKind {
             _ => unreachable!(),
         }
     }
+
+    fn closing(&self) -> char {
+        match self {
+            QuoteKind::SingleQuote => '\'',
+            QuoteKind::DoubleQuote => '"',
+        }
+    }
 }
 
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,
+    BlockComment,
+}
+
+/// Source text between tokens that doesn't affect the program
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+/// The exact source of a token, kept by a lossless lexer
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
+pub struct TokenSource {
+    /// Whitespace and comments between the previous token and this one
+    pub leading_trivia: Vec<Trivia>,
+    /// The token as written, before unescaping
+    pub text: String,
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct Token {
     pub token_kind: TokenKind,
     pos: Pos,
+    /// Only kept by `Lexer::lossless`
+    pub source: Option<TokenSource>,
 }
 
 impl Token {
     pub fn new(token_kind: TokenKind, pos: Pos) -> Token {
-        Token { token_kind, pos }
+        Token {
+            token_kind,
+            pos,
+            source: None,
+        }
+    }
+
+    pub fn pos(&self) -> Pos {
+        self.pos
     }
 }
 
-macro_rules! double_identifier {
-    ( $i:literal, $cur:ident, $idents:ident, $self:ident ) => {{
-        if $cur == $i {
-            $idents.push(Token {
-                token_kind: TokenKind::new(concat!($i, $i)),
-                pos: $self.pos,
-            });
-            $cur = "";
-            continue;
+/// Rebuilds the source text of tokens from `Lexer::lossless`
+#[cfg(test)]
+pub fn reconstruct(tokens: &[Token]) -> String {
+    let mut src = String::new();
+    for source in tokens.iter().filter_map(|t| t.source.as_ref()) {
+        for trivia in &source.leading_trivia {
+            src.push_str(&trivia.text);
         }
+        src.push_str(&source.text);
+    }
+    src
+}
 
-        if $cur != "" {
-            $idents.push(Token {
-                token_kind: TokenKind::new($cur),
-                pos: $self.pos,
-            });
-        }
+#[derive(Debug)]
+pub struct Lexer<'a> {
+    src: &'a str,
+    /// Whether to keep the source of each token, including trivia
+    lossless: bool,
+    /// Byte offset of the next character in `src`
+    i: usize,
+    /// Position of the next character in `src`
+    pos: Pos,
+}
 
-        $cur = $i;
-    }};
+/// Whether `c` can start an identifier or keyword: `_` or anything in
+/// Unicode's `XID_Start`, which is letters
+pub(crate) fn is_ident_start(c: char) -> bool {
+    c == '_' || unicode_ident::is_xid_start(c)
 }
 
-impl Lexer {
-    pub fn new() -> Lexer {
-        Lexer { pos: Pos::new() }
-    }
+/// Whether `c` can continue an identifier: anything in `XID_Continue`, which
+/// adds digits, `_` and combining marks to `XID_Start`
+pub(crate) fn is_ident_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
 
-    fn strip_comments(input: &str) -> Result<String, regex::Error> {
-        let single_line = regex::Regex::new(r"//[^\n\r]*")?;
-        let multi_line = regex::Regex::new(r"/\*[^*]*\*+(?:[^/*][^*]*\*+)*/")?;
+fn is_symbol_char(c: char) -> bool {
+    "{}()[];:,^~=!+-*/<>&|".contains(c)
+}
 
-        Ok(single_line
-            .replace_all(multi_line.replace_all(input, "").to_mut(), "")
-            .to_mut()
-            .to_string())
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Lexer<'a> {
+        Lexer {
+            src,
+            lossless: false,
+            i: 0,
+            pos: Pos::new(),
+        }
     }
 
-    pub fn lex(&mut self, s: &str) -> Result<Vec<Token>, LexingError> {
-        let input = Lexer::strip_comments(s)?;
-        let mut tokens: Vec<Token> = Vec::with_capacity(40);
-
-        let mut ci: String;
-
-        let mut current_identifier: &str = "";
-
-        let mut literal = LiteralKind::None;
+    /// A lexer whose tokens keep their source text and the whitespace and
+    /// comments before them, so `reconstruct` gives back the input exactly.
+    /// The last token is an `Eof` holding any trivia at the end of the input.
+    pub fn lossless(src: &'a str) -> Lexer<'a> {
+        Lexer {
+            lossless: true,
+            ..Lexer::new(src)
+        }
+    }
 
-        let mut integer_base: u32 = 10;
-        let mut char_is_escaped = false;
+    /// Splits the source into tokens in a single pass. Comments are skipped
+    /// where they start, so `//` inside a string is left alone.
+    pub fn lex(&mut self) -> Result<Vec<Token>, LexingError> {
+        self.tokens(None)
+    }
 
-        for c in input.chars() {
-            self.pos.col += 1;
-            match literal {
-                LiteralKind::Str(ref quote_kind) => {
-                    match c {
-                        '"' | '\'' => {
-                            if !char_is_escaped && quote_kind == &QuoteKind::new(c) {
-                                tokens.push(Token {
-                                    token_kind: TokenKind::Literal(Literal::Str(
-                                        current_identifier.to_owned(),
-                                    )),
-                                    pos: self.pos,
-                                });
-                                current_identifier = "";
-                                self.pos.span = 0;
-                                literal = LiteralKind::None;
-                                continue;
-                            }
-                        }
-                        '\\' => {
-                            char_is_escaped = !char_is_escaped;
-                            if char_is_escaped {
-                                continue;
-                            }
-                        }
-                        'n' | 'r' | 't' => {
-                            if char_is_escaped {
-                                let escaped_char: char = match c {
-                                    'n' => '\n',
-                                    'r' => '\r',
-                                    't' => '\t',
-                                    _ => unreachable!(),
-                                };
-                                ci = format!("{}{}", current_identifier, escaped_char);
-                                current_identifier = ci.as_ref();
-                                char_is_escaped = false;
-                                continue;
-                            }
+    /// Lexes tokens up to the end of the input or, for the code of an
+    /// interpolation in the string starting at `interpolation`, up to the `}`
+    /// that closes it
+    fn tokens(&mut self, interpolation: Option<Pos>) -> Result<Vec<Token>, LexingError> {
+        let mut tokens = Vec::new();
+        let mut trivia = Vec::new();
+        let mut depth: usize = 0;
+        loop {
+            self.skip_trivia(&mut trivia)?;
+            let start = self.pos;
+            let start_i = self.i;
+            let c = match self.peek() {
+                Some(c) => c,
+                None => match interpolation {
+                    Some(pos) => return Err(LexingError::UnterminatedString(pos)),
+                    None => {
+                        if self.lossless {
+                            let mut eof = Token::new(TokenKind::Eof, start);
+                            eof.source = Some(TokenSource {
+                                leading_trivia: trivia,
+                                text: String::new(),
+                            });
+                            tokens.push(eof);
                         }
-                        _ => {}
+                        return Ok(tokens);
                     }
-
-                    ci = format!("{}{}", current_identifier, c);
-                    current_identifier = ci.as_ref();
-                    continue;
+                },
+            };
+            let token_kind = match c {
+                '}' if depth == 0 && interpolation.is_some() => {
+                    self.bump();
+                    return Ok(tokens);
                 }
-                LiteralKind::Int => match c {
-                    '0'..='9' => {
-                        ci = format!("{}{}", current_identifier, c);
-                        current_identifier = ci.as_ref();
-                        continue;
-                    }
-                    'a'..='f' | 'A'..='F' => {
-                        if integer_base == 16 {
-                            ci = format!("{}{}", current_identifier, c);
-                            current_identifier = ci.as_ref();
-                        } else {
-                            return Err(LexingError::InvalidIntegerLiteralCharacter(line!()));
-                        }
-                        continue;
-                    }
-                    'x' => {
-                        if current_identifier.len() == 1 {
-                            current_identifier = "";
-                            self.pos.span = 0;
-                            integer_base = 16;
-                        } else {
-                            return Err(LexingError::InvalidIntegerLiteralCharacter(line!()));
-                        }
-                        continue;
-                    }
-                    'g'..='z' | 'G'..='Z' => {
-                        return Err(LexingError::InvalidIntegerLiteralCharacter(line!()));
-                    }
-                    _ => {
-                        tokens.push(Token {
-                            token_kind: TokenKind::Literal(Literal::Int(u64::from_str_radix(
-                                current_identifier,
-                                integer_base,
-                            )?)),
-                            pos: self.pos,
-                        });
-                        integer_base = 10;
-                        current_identifier = "";
-                        self.pos.span = 0;
-                        literal = LiteralKind::None;
-                    }
+                '"' | '\'' => self.string(false)?,
+                // `r"C:\dir"` is a raw string, without escapes or interpolation
+                'r' if matches!(self.peek_nth(1), Some('"') | Some('\'')) => {
+                    self.bump();
+                    self.string(true)?
+                }
+                '`' => self.command()?,
+                '0'..='9' => self.int()?,
+                _ if is_ident_start(c) => TokenKind::new(self.eat_while(is_ident_continue)),
+                _ if is_symbol_char(c) => self.symbol(),
+                _ => return Err(LexingError::InvalidCharacter(c, Pos { span: 1, ..start })),
+            };
+            if interpolation.is_some() {
+                match token_kind {
+                    TokenKind::Symbol(Symbol::OpenBracket) => depth += 1,
+                    TokenKind::Symbol(Symbol::CloseBracket) => depth -= 1,
+                    _ => {}
+                }
+            }
+            let text = &self.src[start_i..self.i];
+            let mut token = Token::new(
+                token_kind,
+                Pos {
+                    span: text.chars().count() as u32,
+                    ..start
                 },
-                _ => {}
+            );
+            if self.lossless {
+                token.source = Some(TokenSource {
+                    leading_trivia: std::mem::take(&mut trivia),
+                    text: text.to_owned(),
+                });
             }
+            tokens.push(token);
+        }
+    }
 
-            match c {
-                ' ' | '\r' | '\t' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
-                        current_identifier = "";
-                        self.pos.span = 0;
-                    }
+    /// Skips whitespace and comments, adding them to `trivia` in lossless mode
+    fn skip_trivia(&mut self, trivia: &mut Vec<Trivia>) -> Result<(), LexingError> {
+        loop {
+            let start = self.i;
+            let kind = match (self.peek(), self.peek_nth(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.eat_while(char::is_whitespace);
+                    TriviaKind::Whitespace
                 }
-                ':' => {
-                    tokens.push(Token {
-                        token_kind: TokenKind::new(current_identifier),
-                        pos: self.pos,
-                    });
-                    self.pos.col += 1;
-                    tokens.push(Token {
-                        token_kind: TokenKind::Symbol(Symbol::Colon),
-                        pos: self.pos,
-                    });
-                    current_identifier = "";
+                // editors on Windows like to start files with a byte order mark
+                (Some('\u{feff}'), _) if self.i == 0 => {
+                    self.bump();
+                    TriviaKind::Whitespace
                 }
-                '\n' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
-                        current_identifier = "";
-                    }
-                    self.pos.span = 0;
-                    self.pos.row += 1;
-                    self.pos.col = 0;
+                (Some('/'), Some('/')) => {
+                    self.eat_while(|c| c != '\n');
+                    TriviaKind::LineComment
                 }
-                '{' | '}' | '(' | ')' | ';' | '^' | ',' | '~' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
-                        current_identifier = "";
-                        self.pos.span = 0;
-                    }
-                    tokens.push(Token {
-                        token_kind: TokenKind::new(&c.to_string()),
-                        pos: self.pos,
-                    });
+                (Some('/'), Some('*')) => {
+                    self.block_comment()?;
+                    TriviaKind::BlockComment
                 }
-                '+' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
-                    }
-                    current_identifier = "+";
+                _ => return Ok(()),
+            };
+            if self.lossless {
+                trivia.push(Trivia {
+                    kind,
+                    text: self.src[start..self.i].to_owned(),
+                });
+            }
+        }
+    }
+
+    /// Skips a block comment, including any block comments nested inside it
+    fn block_comment(&mut self) -> Result<(), LexingError> {
+        let start = self.pos;
+        let mut depth: usize = 0;
+        loop {
+            match (self.bump(), self.peek()) {
+                (Some('/'), Some('*')) => {
+                    self.bump();
+                    depth += 1;
                 }
-                '-' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
+                (Some('*'), Some('/')) => {
+                    self.bump();
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
                     }
-                    current_identifier = "-";
                 }
-                '"' | '\'' => {
-                    literal = LiteralKind::Str(QuoteKind::new(c));
-                    continue;
+                (Some(_), _) => {}
+                (None, _) => return Err(LexingError::UnterminatedComment(start)),
+            }
+        }
+    }
+
+    fn symbol(&mut self) -> TokenKind {
+        let start = self.i;
+        let c = self.bump();
+        let is_pair = matches!(
+            (c, self.peek()),
+            (Some('='), Some('=' | '>'))
+                | (Some('!' | '+' | '-' | '*' | '/' | '<' | '>'), Some('='))
+                | (Some('*'), Some('*'))
+                | (Some(':'), Some(':'))
+                | (Some('&'), Some('&'))
+                | (Some('|'), Some('|'))
+                | (Some('<'), Some('<'))
+                | (Some('>'), Some('>'))
+        );
+        if is_pair {
+            self.bump();
+        }
+        TokenKind::new(&self.src[start..self.i])
+    }
+
+    fn int(&mut self) -> Result<TokenKind, LexingError> {
+        let start = self.pos;
+        let base = match (self.peek(), self.peek_nth(1)) {
+            (Some('0'), Some('x')) => 16,
+            (Some('0'), Some('o')) => 8,
+            (Some('0'), Some('b')) => 2,
+            _ => 10,
+        };
+        if base != 10 {
+            self.bump();
+            self.bump();
+        }
+        // `_` separates digits, as in `1_000_000`
+        let digits = self.eat_while(|c| c.is_digit(base) || c == '_');
+        if self.peek().is_some_and(is_ident_continue) {
+            return Err(LexingError::InvalidIntegerLiteralCharacter(start));
+        }
+        let value = if digits.contains('_') {
+            u64::from_str_radix(&digits.replace('_', ""), base)
+        } else {
+            u64::from_str_radix(digits, base)
+        };
+        let value = value.map_err(|e| LexingError::ParseIntError(e, start))?;
+        Ok(TokenKind::Literal(Literal::Int(value)))
+    }
+
+    /// Lexes a string literal, starting at its opening quote
+    fn string(&mut self, raw: bool) -> Result<TokenKind, LexingError> {
+        let start = self.pos;
+        let quote = match self.bump() {
+            Some(q) => QuoteKind::new(q),
+            None => unreachable!(),
+        };
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        loop {
+            let c = self.bump().ok_or(LexingError::UnterminatedString(start))?;
+            match c {
+                _ if c == quote.closing() => {
+                    return Ok(TokenKind::Literal(Literal::string(&mut parts, &text)));
                 }
-                '=' => match current_identifier {
-                    "=" | "+" | "-" | "*" | "/" | "<" | ">" | "!" => {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(&format!("{}{}", current_identifier, "=")),
-                            pos: self.pos,
-                        });
-                        current_identifier = "";
-                        self.pos.span = 0;
-                    }
-                    _ => {
-                        if current_identifier != "" {
-                            tokens.push(Token {
-                                token_kind: TokenKind::new(current_identifier),
-                                pos: self.pos,
-                            });
-                        }
-                        current_identifier = "=";
-                    }
-                },
-                '&' => double_identifier!("&", current_identifier, tokens, self),
-                '|' => double_identifier!("|", current_identifier, tokens, self),
-                '*' => double_identifier!("*", current_identifier, tokens, self),
-                '/' => double_identifier!("/", current_identifier, tokens, self),
-                '>' => double_identifier!(">", current_identifier, tokens, self),
-                '<' => double_identifier!("<", current_identifier, tokens, self),
-                '!' => {
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
+                _ if raw => text.push(c),
+                '\\' => text.push(self.escape(start)?),
+                '{' => {
+                    if !text.is_empty() {
+                        parts.push(StrPart::Text(std::mem::take(&mut text)));
                     }
-                    current_identifier = "!";
+                    parts.push(StrPart::Code(self.tokens(Some(start))?));
                 }
-                '0'..='9' => {
-                    literal = LiteralKind::Int;
-                    if current_identifier != "" {
-                        tokens.push(Token {
-                            token_kind: TokenKind::new(current_identifier),
-                            pos: self.pos,
-                        });
-                        current_identifier = "";
-                        self.pos.span = 0;
+                _ => text.push(c),
+            }
+        }
+    }
+
+    /// Lexes a command line, starting at its opening backtick. Backslashes
+    /// are kept as they are, for Windows paths, unless they escape a
+    /// backtick or a brace
+    fn command(&mut self) -> Result<TokenKind, LexingError> {
+        let start = self.pos;
+        self.bump();
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        loop {
+            let c = self.bump().ok_or(LexingError::UnterminatedString(start))?;
+            match c {
+                '`' => {
+                    if !text.is_empty() {
+                        parts.push(StrPart::Text(text));
                     }
-                    ci = format!("{}{}", current_identifier, c);
-                    current_identifier = ci.as_ref();
+                    return Ok(TokenKind::Literal(Literal::Command(parts)));
                 }
-                _ => {
-                    match current_identifier {
-                        "=" | "&" | "|" | "*" | "/" | "<" | ">" | "+" | "-" | "!" => {
-                            tokens.push(Token {
-                                token_kind: TokenKind::new(current_identifier),
-                                pos: self.pos,
-                            });
-                            current_identifier = "";
-                            self.pos.span = 0;
-                        }
-                        _ => {}
+                '\\' if matches!(self.peek(), Some('`') | Some('{') | Some('}')) => {
+                    text.extend(self.bump());
+                }
+                '{' => {
+                    if !text.is_empty() {
+                        parts.push(StrPart::Text(std::mem::take(&mut text)));
                     }
-                    ci = format!("{}{}", current_identifier, c);
-                    current_identifier = ci.as_ref();
+                    parts.push(StrPart::Code(self.tokens(Some(start))?));
                 }
+                _ => text.push(c),
             }
-            self.pos.span += 1;
         }
+    }
 
-        match literal {
-            LiteralKind::Int => {
-                tokens.push(Token {
-                    token_kind: TokenKind::Literal(Literal::Int(u64::from_str_radix(
-                        current_identifier,
-                        integer_base,
-                    )?)),
-                    pos: self.pos,
-                });
+    /// Reads the escape sequence after a `\` in the string starting at `start`
+    fn escape(&mut self, start: Pos) -> Result<char, LexingError> {
+        let pos = self.pos;
+        let c = self.bump().ok_or(LexingError::UnterminatedString(start))?;
+        Ok(match c {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' | '"' | '\'' | '{' | '}' => c,
+            'x' => {
+                let digits_start = self.i;
+                self.bump();
+                self.bump();
+                u8::from_str_radix(&self.src[digits_start..self.i], 16)
+                    .ok()
+                    .filter(u8::is_ascii)
+                    .map(char::from)
+                    .ok_or(LexingError::InvalidEscape('x', pos))?
             }
-            LiteralKind::Str(_) => {
-                tokens.push(Token {
-                    token_kind: TokenKind::Literal(Literal::Str(current_identifier.to_owned())),
-                    pos: self.pos,
-                });
-            }
-            _ => {
-                if current_identifier != "" {
-                    tokens.push(Token {
-                        token_kind: TokenKind::new(current_identifier),
-                        pos: self.pos,
-                    });
+            'u' => {
+                let invalid = LexingError::InvalidEscape('u', pos);
+                if self.bump() != Some('{') {
+                    return Err(invalid);
+                }
+                let code = self.eat_while(|c| c.is_ascii_hexdigit());
+                if self.bump() != Some('}') || code.is_empty() || code.len() > 6 {
+                    return Err(invalid);
                 }
+                u32::from_str_radix(code, 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                    .ok_or(invalid)?
+            }
+            _ => return Err(LexingError::InvalidEscape(c, pos)),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.i..].chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.src[self.i..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.i += c.len_utf8();
+        if c == '\n' {
+            self.pos.row += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+
+    /// Consumes characters while `f` holds, returning the text consumed
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.i;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        &self.src[start..self.i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<TokenKind> {
+        Lexer::new(src)
+            .lex()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_kind)
+            .collect()
+    }
+
+    #[test]
+    fn identifiers_can_have_combining_marks() {
+        for name in &["cafe\u{301}", "\u{915}\u{94d}\u{937}", "_x1"] {
+            assert_eq!(kinds(name)[0], TokenKind::Identifier((*name).to_owned()));
+        }
+    }
+
+    fn string(src: &str) -> String {
+        match kinds(src).remove(0) {
+            TokenKind::Literal(Literal::Str(s)) => s,
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(string(r#""a\n\r\t\0""#), "a\n\r\t\0");
+        assert_eq!(string(r#""\\ \" \' \{ \}""#), "\\ \" ' { }");
+        assert_eq!(string(r#""\x41\u{263A}\u{1F600}""#), "A\u{263A}\u{1F600}");
+        assert_eq!(string(r#"'it\'s'"#), "it's");
+        assert_eq!(string(r#"r"C:\Users\{name}""#), r"C:\Users\{name}");
+    }
+
+    #[test]
+    fn lossless_tokens_reconstruct_the_source() {
+        for src in &[
+            "",
+            "  // only a comment\n",
+            "{\r\n    // line\r\n    let x = 0x1F; /* block /* nested */ */\r\n}\r\n",
+            "print(\"a\\n{x + 1} \\u{263A}\\\"\");\t\n",
+            "let p = r\"C:\\{dir}\"; let q = 'it\\'s';",
+        ] {
+            let tokens = Lexer::lossless(src).lex().unwrap();
+            assert_eq!(reconstruct(&tokens), *src);
+        }
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        let kinds = kinds("a /* b /* c */ d */ e // f /* g\nh");
+        let names: Vec<&str> = kinds
+            .iter()
+            .filter_map(|k| match k {
+                TokenKind::Identifier(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, ["a", "e", "h"]);
+        assert!(matches!(
+            Lexer::new("a /* b /* c */").lex(),
+            Err(LexingError::UnterminatedComment(_))
+        ));
+    }
+
+    #[test]
+    fn comment_markers_in_strings_are_text() {
+        assert_eq!(string(r#""http://x /* y */""#), "http://x /* y */");
+        assert_eq!(string(r#"r"C://dir""#), "C://dir");
+    }
+
+    #[test]
+    fn integer_literals() {
+        let ints: Vec<TokenKind> = [5, 15, 31, 1_000_000, 0]
+            .iter()
+            .map(|&i| TokenKind::Literal(Literal::Int(i)))
+            .collect();
+        assert_eq!(kinds("0b101 0o17 0x1F 1_000_000 0")[..5], ints[..]);
+    }
+
+    #[test]
+    fn invalid_integers_point_at_the_literal() {
+        match Lexer::new("let x =\n    0b12;").lex() {
+            Err(LexingError::InvalidIntegerLiteralCharacter(pos)) => assert_eq!(pos.to_string(), "2:5"),
+            other => panic!("{:?}", other),
+        }
+        match Lexer::new("x = 99999999999999999999;").lex() {
+            Err(LexingError::ParseIntError(_, pos)) => assert_eq!(pos.to_string(), "1:5"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        for src in &[
+            r#""\q""#,
+            r#""\x80""#,
+            r#""\x4""#,
+            r#""\u{}""#,
+            r#""\u{110000}""#,
+            r#""\u263A""#,
+        ] {
+            match Lexer::new(src).lex() {
+                Err(LexingError::InvalidEscape(..)) => {}
+                other => panic!("{}: {:?}", src, other),
             }
         }
-        Ok(tokens)
+        assert!(matches!(
+            Lexer::new(r#""abc\"#).lex(),
+            Err(LexingError::UnterminatedString(_))
+        ));
     }
 }
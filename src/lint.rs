@@ -0,0 +1,563 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::*;
+use crate::lexer::Pos;
+
+#[derive(Debug, Hash, Eq, PartialEq, PartialOrd, Ord, Copy, Clone)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Level {
+    fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "allow" => Some(Level::Allow),
+            "warn" => Some(Level::Warn),
+            "deny" => Some(Level::Deny),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Allow => "allow",
+            Level::Warn => "warning",
+            Level::Deny => "error",
+        })
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq)]
+pub struct Lint {
+    pub id: &'static str,
+    pub default_level: Level,
+    pub desc: &'static str,
+}
+
+pub static UNUSED_VARIABLES: Lint = Lint {
+    id: "unused_variables",
+    default_level: Level::Warn,
+    desc: "variables that are declared but never read",
+};
+
+pub static UNUSED_FUNCTIONS: Lint = Lint {
+    id: "unused_functions",
+    default_level: Level::Warn,
+    desc: "functions that are defined but never called",
+};
+
+pub static SHADOWED_NAMES: Lint = Lint {
+    id: "shadowed_names",
+    default_level: Level::Warn,
+    desc: "declarations that reuse a name from an enclosing scope; batch has no block scope, so the outer value is overwritten",
+};
+
+pub static CONSTANT_CONDITION: Lint = Lint {
+    id: "constant_condition",
+    default_level: Level::Warn,
+    desc: "`if` and `while` conditions that can never change, including `while` loops that can never exit",
+};
+
+pub static SELF_ASSIGNMENT: Lint = Lint {
+    id: "self_assignment",
+    default_level: Level::Warn,
+    desc: "assigning a variable to itself",
+};
+
+pub static LEXICAL_COMPARISON: Lint = Lint {
+    id: "lexical_comparison",
+    default_level: Level::Warn,
+    desc: "ordering comparisons against strings, which cmd performs lexically rather than numerically",
+};
+
+pub static CMD_BUILTIN_NAMES: Lint = Lint {
+    id: "cmd_builtin_names",
+    default_level: Level::Warn,
+    desc: "exported variables that collide with cmd built-in variables such as `path` or `errorlevel`",
+};
+
+pub static CASE_COLLISIONS: Lint = Lint {
+    id: "case_collisions",
+    default_level: Level::Warn,
+    desc: "exported variables whose names differ only by case, which cmd treats as the same variable",
+};
+
+pub static NON_EXHAUSTIVE_MATCH: Lint = Lint {
+    id: "non_exhaustive_match",
+    default_level: Level::Warn,
+    desc: "`match` statements without a `_` arm, which silently do nothing for unlisted values",
+};
+
+pub static UNKNOWN_LINTS: Lint = Lint {
+    id: "unknown_lints",
+    default_level: Level::Warn,
+    desc: "lint pragmas naming a lint that does not exist",
+};
+
+pub static LINTS: &[&Lint] = &[
+    &UNUSED_VARIABLES,
+    &UNUSED_FUNCTIONS,
+    &SHADOWED_NAMES,
+    &CONSTANT_CONDITION,
+    &SELF_ASSIGNMENT,
+    &LEXICAL_COMPARISON,
+    &CMD_BUILTIN_NAMES,
+    &CASE_COLLISIONS,
+    &NON_EXHAUSTIVE_MATCH,
+    &UNKNOWN_LINTS,
+];
+
+/// Dynamic variables and well known environment variables that cmd either
+/// computes on read or that change the behavior of the running script
+const CMD_BUILTINS: &[&str] = &[
+    "cd",
+    "cmdcmdline",
+    "cmdextversion",
+    "comspec",
+    "date",
+    "errorlevel",
+    "highestnumanodenumber",
+    "path",
+    "pathext",
+    "prompt",
+    "random",
+    "temp",
+    "time",
+    "tmp",
+];
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub lint: &'static Lint,
+    pub level: Level,
+    pub message: String,
+    pub pos: Pos,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}[{}]: {}", self.pos, self.level, self.lint.id, self.message)
+    }
+}
+
+/// The text after `ski:` of each `// ski: ...` comment in `src`
+pub fn pragmas(src: &str) -> impl Iterator<Item = &str> {
+    pragma_lines(src).map(|(_, pragma)| pragma)
+}
+
+/// Like `pragmas`, with the line each pragma is written on
+fn pragma_lines(src: &str) -> impl Iterator<Item = (Pos, &str)> {
+    src.lines().zip(1..).filter_map(|(line, row)| {
+        let comment = line.trim().strip_prefix("//")?;
+        Some((Pos::line(row), comment.trim().strip_prefix("ski:")?.trim()))
+    })
+}
+
+/// Per file lint levels, set with comment pragmas of the form
+/// `// ski: allow(unused_variables, shadowed_names)`
+#[derive(Debug, Default)]
+pub struct LintConfig {
+    levels: HashMap<&'static str, Level>,
+    unknown: Vec<(String, Pos)>,
+}
+
+impl LintConfig {
+    pub fn from_source(src: &str) -> LintConfig {
+        let mut config = LintConfig::default();
+        for (pos, pragma) in pragma_lines(src) {
+            let open = match pragma.find('(') {
+                Some(i) if pragma.ends_with(')') => i,
+                _ => continue,
+            };
+            let level = match Level::from_name(pragma[..open].trim()) {
+                Some(l) => l,
+                None => continue,
+            };
+            for name in pragma[open + 1..pragma.len() - 1].split(',') {
+                let name = name.trim();
+                match LINTS.iter().find(|l| l.id == name) {
+                    Some(lint) => {
+                        config.levels.insert(lint.id, level);
+                    }
+                    None => config.unknown.push((name.to_owned(), pos)),
+                }
+            }
+        }
+        config
+    }
+
+    pub fn level(&self, lint: &Lint) -> Level {
+        *self.levels.get(lint.id).unwrap_or(&lint.default_level)
+    }
+}
+
+#[derive(Debug)]
+pub struct Linter {
+    config: LintConfig,
+    diagnostics: Vec<Diagnostic>,
+    scopes: Vec<HashSet<String>>,
+    /// The variables read in the file and in each enclosing function, since
+    /// a function's variables can only be read inside it
+    reads: Vec<HashSet<String>>,
+    calls: HashSet<String>,
+    /// Every exported variable name seen so far, keyed by its lowercase form
+    folded_names: HashMap<String, String>,
+}
+
+impl Linter {
+    pub fn new(config: LintConfig) -> Linter {
+        Linter {
+            config,
+            diagnostics: Vec::new(),
+            scopes: vec![HashSet::new()],
+            reads: Vec::new(),
+            calls: HashSet::new(),
+            folded_names: HashMap::new(),
+        }
+    }
+
+    pub fn check(mut self, ast: &Expr) -> Vec<Diagnostic> {
+        for (name, pos) in std::mem::take(&mut self.config.unknown) {
+            self.emit(&UNKNOWN_LINTS, SourcePos(pos), format!("unknown lint `{}`", name));
+        }
+        let mut reads = HashSet::new();
+        collect_uses(ast, &mut reads, &mut self.calls);
+        self.reads.push(reads);
+        self.check_expr(ast);
+        self.diagnostics
+    }
+
+    fn emit(&mut self, lint: &'static Lint, pos: SourcePos, message: String) {
+        let level = self.config.level(lint);
+        if level != Level::Allow {
+            self.diagnostics.push(Diagnostic {
+                lint,
+                level,
+                message,
+                pos: pos.0,
+            });
+        }
+    }
+
+    fn declare(&mut self, name: &str, is_exported: bool, pos: SourcePos) {
+        let folded = name.to_lowercase();
+        if is_exported && CMD_BUILTINS.contains(&folded.as_str()) {
+            self.emit(
+                &CMD_BUILTIN_NAMES,
+                pos,
+                format!("`{}` collides with the cmd variable `%{}%`", name, name.to_uppercase()),
+            );
+        }
+        if is_exported {
+            match self.folded_names.get(&folded) {
+                Some(other) if other != name => {
+                    let message = format!("`{}` and `{}` are the same variable in cmd", other, name);
+                    self.emit(&CASE_COLLISIONS, pos, message);
+                }
+                Some(_) => {}
+                None => {
+                    self.folded_names.insert(folded, name.to_owned());
+                }
+            }
+        }
+        if self.scopes.iter().any(|s| s.contains(name)) {
+            self.emit(&SHADOWED_NAMES, pos, format!("`{}` shadows an earlier declaration", name));
+        }
+        self.scopes.last_mut().unwrap().insert(name.to_owned());
+    }
+
+    fn check_var_decl(&mut self, name: &str, value: &Expr, is_exported: bool, pos: SourcePos) {
+        self.check_expr(value);
+        if value == &Expr::Variable(name.to_owned()) {
+            self.emit(&SELF_ASSIGNMENT, pos, format!("`{}` is assigned to itself", name));
+        }
+        if !self.reads.last().is_some_and(|r| r.contains(name)) {
+            self.emit(&UNUSED_VARIABLES, pos, format!("variable `{}` is never used", name));
+        }
+        self.declare(name, is_exported, pos);
+    }
+
+    fn check_scoped(&mut self, names: &[String], pos: SourcePos, body: &Expr) {
+        self.scopes.push(HashSet::new());
+        for name in names {
+            self.declare(name, false, pos);
+        }
+        self.check_expr(body);
+        self.scopes.pop();
+    }
+
+    fn check_block(&mut self, body: &Expr) {
+        self.check_scoped(&[], SourcePos(Pos::new()), body);
+    }
+
+    fn check_expr(&mut self, ast: &Expr) {
+        match ast {
+            Expr::Int(_) | Expr::Str(_) | Expr::Bool(_) | Expr::Variable(_) => {}
+            Expr::Continue | Expr::Break => {}
+            Expr::Import(_) | Expr::Use(_) | Expr::Extern(_) => {}
+            Expr::Unary(u) => self.check_expr(&u.child),
+            Expr::Binary(b) => {
+                self.check_expr(&b.left);
+                self.check_expr(&b.right);
+                self.check_binary(b);
+            }
+            Expr::Return(e) | Expr::Paren(e) => self.check_expr(e),
+            Expr::VariableDecl(v) => self.check_var_decl(&v.name, &v.value, v.is_exported, v.pos),
+            Expr::If(i) => {
+                if is_constant(&i.cond) {
+                    self.emit(&CONSTANT_CONDITION, i.pos, "`if` condition is constant".to_owned());
+                }
+                self.check_expr(&i.cond);
+                self.check_block(&i.then);
+                self.check_block(&i.else_);
+            }
+            Expr::FuncDef(f) => {
+                if f.name != "main" && !self.calls.contains(&f.name) {
+                    let message = format!("function `{}` is never called", f.name);
+                    self.emit(&UNUSED_FUNCTIONS, f.pos, message);
+                }
+                let mut reads = HashSet::new();
+                collect_uses(&f.body, &mut reads, &mut HashSet::new());
+                self.reads.push(reads);
+                self.check_scoped(&f.params, f.pos, &f.body);
+                self.reads.pop();
+            }
+            Expr::FuncCall(c) => {
+                for p in &c.params {
+                    self.check_expr(p);
+                }
+            }
+            Expr::Array(items) | Expr::Interpolated(items) => {
+                for item in items {
+                    self.check_expr(item);
+                }
+            }
+            Expr::Command(_) => {
+                for child in ast.children() {
+                    self.check_expr(child);
+                }
+            }
+            Expr::While(w) => {
+                if is_constant(&w.cond) && !has_break(&w.body) {
+                    self.emit(
+                        &CONSTANT_CONDITION,
+                        w.pos,
+                        "`while` condition is constant and the loop has no `break`".to_owned(),
+                    );
+                }
+                self.check_expr(&w.cond);
+                self.check_block(&w.body);
+            }
+            Expr::Loop(l) => self.check_block(&l.body),
+            Expr::Spawn(body) => self.check_block(body),
+            Expr::Match(m) => {
+                if m.default.is_none() {
+                    self.emit(&NON_EXHAUSTIVE_MATCH, m.pos, "`match` has no `_` arm".to_owned());
+                }
+                self.check_expr(&m.value);
+                for arm in &m.arms {
+                    self.check_block(&arm.body);
+                }
+                if let Some(ref default) = m.default {
+                    self.check_block(default);
+                }
+            }
+            Expr::Try(t) => {
+                self.check_block(&t.body);
+                if let Some((ref name, ref body)) = t.catch {
+                    self.check_scoped(std::slice::from_ref(name), t.pos, body);
+                }
+                if let Some(ref finally) = t.finally {
+                    self.check_block(finally);
+                }
+            }
+            Expr::Flags(flags) => {
+                for flag in flags {
+                    self.declare(&flag.name, false, flag.pos);
+                }
+            }
+            Expr::For(f) => {
+                self.check_expr(&f.container);
+                self.check_scoped(std::slice::from_ref(&f.item), f.pos, &f.body);
+            }
+            Expr::Block(stmts) => {
+                self.scopes.push(HashSet::new());
+                for s in stmts {
+                    self.check_expr(s);
+                }
+                self.scopes.pop();
+            }
+        }
+    }
+
+    fn check_binary(&mut self, b: &BinaryExpr) {
+        match b.op {
+            BinaryOpKind::Assign if b.left == b.right => {
+                self.emit(&SELF_ASSIGNMENT, b.pos, "value is assigned to itself".to_owned());
+            }
+            BinaryOpKind::Gt | BinaryOpKind::Lt | BinaryOpKind::GtEq | BinaryOpKind::LtEq => {
+                if !matches!(b.left, Expr::Str(_)) && !matches!(b.right, Expr::Str(_)) {
+                    return;
+                }
+                self.emit(
+                    &LEXICAL_COMPARISON,
+                    b.pos,
+                    "comparison against a string is performed lexically, so \"10\" < \"9\"".to_owned(),
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_uses(ast: &Expr, reads: &mut HashSet<String>, calls: &mut HashSet<String>) {
+    match ast {
+        Expr::Variable(name) => {
+            reads.insert(name.clone());
+        }
+        Expr::FuncCall(c) => {
+            calls.insert(c.func_name.clone());
+            for p in &c.params {
+                collect_uses(p, reads, calls);
+            }
+        }
+        _ => ast
+            .children()
+            .into_iter()
+            .for_each(|child| collect_uses(child, reads, calls)),
+    }
+}
+
+/// An expression whose value cannot change between evaluations
+fn is_constant(ast: &Expr) -> bool {
+    match ast {
+        Expr::Int(_) | Expr::Str(_) | Expr::Bool(_) => true,
+        Expr::Unary(u) => is_constant(&u.child),
+        Expr::Binary(b) => is_constant(&b.left) && is_constant(&b.right),
+        Expr::Paren(e) => is_constant(e),
+        _ => false,
+    }
+}
+
+/// Whether `body` contains a `break` or `return` that leaves the current loop
+fn has_break(body: &Expr) -> bool {
+    match body {
+        Expr::Break | Expr::Return(_) => true,
+        Expr::While(_) | Expr::Loop(_) | Expr::For(_) | Expr::FuncDef(_) => false,
+        _ => body.children().into_iter().any(has_break),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn lint(src: &str) -> Vec<(&'static str, Level)> {
+        let ast = Parser::new(Lexer::new(src).lex().unwrap()).parse().unwrap();
+        Linter::new(LintConfig::from_source(src))
+            .check(&ast)
+            .into_iter()
+            .map(|d| (d.lint.id, d.level))
+            .collect()
+    }
+
+    fn ids(src: &str) -> Vec<&'static str> {
+        lint(src).into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn unused_variables() {
+        assert_eq!(ids("{ let x = 1; }"), ["unused_variables"]);
+        assert!(ids("{ let x = 1; print(x); }").is_empty());
+    }
+
+    #[test]
+    fn unused_variables_are_scoped_to_their_function() {
+        let src = "{ fn f() { let x = 1; } fn g() { let x = 2; print(x); } f(); g(); }";
+        assert_eq!(ids(src), ["unused_variables"]);
+        assert!(ids("{ let x = 1; fn f() { print(x); } f(); }").is_empty());
+    }
+
+    #[test]
+    fn diagnostics_have_positions() {
+        let src = "// ski: allow(nope)\n{\n    let x = 1;\n    if 1 == 1 { print(\"a\"); }\n}";
+        let ast = Parser::new(Lexer::new(src).lex().unwrap()).parse().unwrap();
+        let diagnostics: Vec<String> = Linter::new(LintConfig::from_source(src))
+            .check(&ast)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "1:1: warning[unknown_lints]: unknown lint `nope`",
+                "3:9: warning[unused_variables]: variable `x` is never used",
+                "4:5: warning[constant_condition]: `if` condition is constant",
+            ]
+        );
+    }
+
+    #[test]
+    fn unused_functions() {
+        assert_eq!(ids("{ fn f() { } }"), ["unused_functions"]);
+        assert!(ids("{ fn f() { } f(); }").is_empty());
+    }
+
+    #[test]
+    fn shadowed_names() {
+        let src = "{ let x = 1; if x == 1 { let x = 2; print(x); } print(x); }";
+        assert_eq!(ids(src), ["shadowed_names"]);
+    }
+
+    #[test]
+    fn constant_condition() {
+        let src = "{ if 1 == 1 { print(\"a\"); } }";
+        assert_eq!(ids(src), ["constant_condition"]);
+    }
+
+    #[test]
+    fn self_assignment() {
+        assert!(ids("{ let x = 1; let x = x; print(x); }").contains(&"self_assignment"));
+    }
+
+    #[test]
+    fn lexical_comparison() {
+        let src = "{ let x = 1; if x < \"a\" { print(x); } }";
+        assert_eq!(ids(src), ["lexical_comparison"]);
+    }
+
+    #[test]
+    fn cmd_builtin_names() {
+        let src = "{ export let path = \"a\"; print(path); }";
+        assert_eq!(ids(src), ["cmd_builtin_names"]);
+    }
+
+    #[test]
+    fn case_collisions() {
+        let src = "{ export let Foo = 1; export let foo = 2; print(Foo); print(foo); }";
+        assert_eq!(ids(src), ["case_collisions"]);
+    }
+
+    #[test]
+    fn non_exhaustive_match() {
+        let src = "{ let x = 1; match x { 1 => print(\"a\"), } }";
+        assert_eq!(ids(src), ["non_exhaustive_match"]);
+    }
+
+    #[test]
+    fn pragmas_set_levels() {
+        assert_eq!(ids("// ski: allow(nope)\n{ }"), ["unknown_lints"]);
+        assert!(ids("// ski: allow(unused_variables)\n{ let x = 1; }").is_empty());
+        assert_eq!(
+            lint("// ski: deny(unused_variables)\n{ let x = 1; }"),
+            [("unused_variables", Level::Deny)]
+        );
+    }
+}
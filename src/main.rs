@@ -1,37 +1,146 @@
 #![deny(missing_debug_implementations)]
-#![allow(dead_code, unused_imports)]
+// the compiler takes the boxed nodes of the AST as they are
+#![allow(clippy::boxed_local)]
+use std::env;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{self, stdin};
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
 
 use compiler::Compiler;
 use lexer::Lexer;
+use lint::{Level, LintConfig, Linter};
 use parser::Parser;
 
 mod ast;
 mod compiler;
 mod errors;
+mod format;
 mod lexer;
+mod lint;
+mod mangle;
+mod module;
 mod parser;
 
 fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("lint") {
+        return lint(args.get(1).map(String::as_str).unwrap_or("test.ski"));
+    }
+    if args.first().map(String::as_str) == Some("fmt") {
+        return fmt(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("bench") {
+        return bench(args.get(1).map(String::as_str).unwrap_or("test.ski"));
+    }
+
     let mut f = File::open("test.ski")?;
     let mut input = String::new();
-    let mut lexer = Lexer::new();
     // stdin().read_line(&mut input)?;
     f.read_to_string(&mut input)?;
 
     println!("{}", &input);
 
-    let tokens = lexer.lex(&input).unwrap();
+    let modules = module::load(Path::new("test.ski"))?;
+    let script = modules.last().expect("the script itself is always loaded");
+    println!("{:#?}", script.ast);
 
-    // println!("{:?}", &tokens);
+    let fail_fast = lint::pragmas(&input).any(|p| p == "fail_fast");
+    // with `--split`, each imported module gets a `.cmd` file of its own
+    // next to the script's
+    let split = args.iter().any(|a| a == "--split");
+    if split {
+        for module in &modules[..modules.len() - 1] {
+            Compiler::new(File::create(module.file_name())?)
+                .fail_fast(fail_fast)
+                .split(true)
+                .compile_module(&modules, module)?;
+        }
+    }
+    let mut comp = Compiler::new(File::create("foo.cmd")?)
+        .fail_fast(fail_fast)
+        .split(split);
+    comp.compile(&modules)?;
+    Ok(())
+}
 
-    let mut parser = Parser::new(tokens);
-    let x = parser.parse().unwrap();
-    println!("{:#?}", x);
+fn lint(path: &str) -> io::Result<()> {
+    let mut input = String::new();
+    File::open(path)?.read_to_string(&mut input)?;
+
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path, e));
+    let tokens = Lexer::new(&input).lex().map_err(|e| invalid(e.to_string()))?;
+    let ast = Parser::new(tokens).parse().map_err(|e| invalid(e.to_string()))?;
+
+    let diagnostics = Linter::new(LintConfig::from_source(&input)).check(&ast);
+    for d in &diagnostics {
+        eprintln!("{}: {}", path, d);
+    }
+    if diagnostics.iter().any(|d| d.level == Level::Deny) {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Formats files in place, or with `--check` only reports the ones that
+/// aren't formatted
+fn fmt(args: &[String]) -> io::Result<()> {
+    let check = args.iter().any(|a| a == "--check");
+    let mut failed = false;
+    for path in args.iter().filter(|a| *a != "--check") {
+        let mut input = String::new();
+        File::open(path)?.read_to_string(&mut input)?;
+        let formatted = match format::format(&input) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("{}: could not format: {}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == input {
+            continue;
+        }
+        if check {
+            eprintln!("{}: not formatted", path);
+            failed = true;
+        } else {
+            File::create(path)?.write_all(formatted.as_bytes())?;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Times lexing the file repeated 1, 10, 100 and 1000 times, to show the lexer
+/// stays linear in the size of its input
+fn bench(path: &str) -> io::Result<()> {
+    let mut input = String::new();
+    File::open(path)?.read_to_string(&mut input)?;
+    if let Err(e) = Lexer::new(&input).lex() {
+        let message = format!("{}: {}", path, e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
 
-    let mut comp = Compiler::new(File::create("foo.cmd")?);
-    comp.compile(x)?;
+    for &copies in &[1, 10, 100, 1000] {
+        let source = input.repeat(copies);
+        let runs = 10_000 / copies;
+        let start = Instant::now();
+        for _ in 0..runs {
+            // only the time is measured, the file itself lexed above
+            let _ = Lexer::new(&source).lex();
+        }
+        let per_run = start.elapsed() / runs as u32;
+        println!(
+            "{:>8} bytes: {:>10.1?} per run, {:>6.1} MB/s",
+            source.len(),
+            per_run,
+            source.len() as f64 / per_run.as_secs_f64() / 1e6
+        );
+    }
     Ok(())
 }
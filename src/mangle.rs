@@ -0,0 +1,80 @@
+/// Encodes a case-sensitive ski identifier as a name that stays distinct under
+/// cmd's case-insensitive variable and label lookup.
+///
+/// Uppercase letters become `_` followed by the lowercase letter and `_` is
+/// doubled, so `Foo` is `_foo`, `foo` is `foo` and `_foo` is `__foo`. The
+/// encoding never produces an odd number of `_` followed by a digit, which
+/// leaves names like `__ski_while_3_end` free for compiler generated labels.
+///
+/// Any other character outside ASCII is written as its code point in hex
+/// between `$`s, so `café` is `caf$e9$`. cmd reads scripts in the console's
+/// code page and folds case for non-ASCII letters too, so the characters
+/// themselves can't be written out.
+pub fn mangle(ident: &str) -> String {
+    let mut mangled = String::with_capacity(ident.len());
+    for c in ident.chars() {
+        match c {
+            '_' => mangled.push_str("__"),
+            'A'..='Z' => {
+                mangled.push('_');
+                mangled.push(c.to_ascii_lowercase());
+            }
+            _ if !c.is_ascii() => mangled.push_str(&format!("${:x}$", c as u32)),
+            _ => mangled.push(c),
+        }
+    }
+    mangled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: &[&str] = &[
+        "x",
+        "X",
+        "_x",
+        "_X",
+        "__x",
+        "x_",
+        "X_",
+        "xY",
+        "x_y",
+        "café",
+        "cafe\u{301}",
+        "CAFÉ",
+        "_1",
+        "x_1",
+        "X1",
+    ];
+
+    #[test]
+    fn documented_examples() {
+        assert_eq!(mangle("Foo"), "_foo");
+        assert_eq!(mangle("foo"), "foo");
+        assert_eq!(mangle("_foo"), "__foo");
+        assert_eq!(mangle("café"), "caf$e9$");
+    }
+
+    #[test]
+    fn names_stay_distinct_ignoring_case() {
+        let mut seen = std::collections::HashMap::new();
+        for name in NAMES {
+            if let Some(other) = seen.insert(mangle(name).to_lowercase(), name) {
+                panic!("`{}` and `{}` collide", name, other);
+            }
+        }
+    }
+
+    #[test]
+    fn names_never_look_like_labels() {
+        for name in NAMES {
+            let mangled = mangle(name);
+            let mut run = 0;
+            for c in mangled.chars() {
+                assert!(!(c.is_ascii_digit() && run % 2 == 1), "{}", mangled);
+                run = if c == '_' { run + 1 } else { 0 };
+            }
+        }
+    }
+}
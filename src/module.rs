@@ -0,0 +1,206 @@
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::ast::Expr;
+use crate::lexer::{is_ident_continue, is_ident_start, Lexer};
+use crate::mangle::mangle;
+use crate::parser::Parser;
+
+/// A ski file, named after its file name without the extension
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub path: PathBuf,
+    pub ast: Expr,
+}
+
+impl Module {
+    /// The `.cmd` file the module is written to when modules are split
+    pub fn file_name(&self) -> String {
+        format!("{}.cmd", mangle(&self.name))
+    }
+
+    /// The top level statements of the module
+    pub fn items(&self) -> &[Expr] {
+        match self.ast {
+            Expr::Block(ref stmts) => stmts,
+            ref stmt => std::slice::from_ref(stmt),
+        }
+    }
+}
+
+/// Reads the script at `path` and every file it imports, directly or not.
+///
+/// An import is found relative to the file that imports it, and a file that
+/// is imported more than once is only read once. Every module comes before
+/// the modules that import it, so the script itself is last.
+pub fn load(path: &Path) -> io::Result<Vec<Module>> {
+    let mut loader = Loader {
+        modules: Vec::new(),
+        loaded: Vec::new(),
+        loading: Vec::new(),
+    };
+    loader.load(path.to_owned())?;
+    Ok(loader.modules)
+}
+
+/// The name of the module that `import path` brings in, which is what its
+/// functions are called through
+pub fn name(path: &Path) -> io::Result<String> {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let mut chars = stem.chars();
+    let is_ident = match chars.next() {
+        Some(c) => is_ident_start(c) && chars.all(is_ident_continue),
+        None => false,
+    };
+    if !is_ident {
+        return Err(invalid(
+            path,
+            "the file name has to be a valid identifier".to_owned(),
+        ));
+    }
+    Ok(stem.to_owned())
+}
+
+#[derive(Debug)]
+struct Loader {
+    modules: Vec<Module>,
+    /// The canonical path of each of `modules`
+    loaded: Vec<PathBuf>,
+    /// The canonical paths of the files being loaded, each imported by the
+    /// one before it
+    loading: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn load(&mut self, path: PathBuf) -> io::Result<()> {
+        let canonical = fs::canonicalize(&path).map_err(|e| invalid(&path, e.to_string()))?;
+        if self.loaded.contains(&canonical) {
+            return Ok(());
+        }
+        if let Some(i) = self.loading.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = self.loading[i..]
+                .iter()
+                .chain(Some(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(invalid(
+                &path,
+                format!("import cycle: {}", cycle.join(" -> ")),
+            ));
+        }
+        // the script's own functions aren't namespaced, so any name will do
+        let is_script = self.loading.is_empty();
+        let name = if is_script {
+            path.file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            let name = name(&path)?;
+            if let Some(other) = self.modules.iter().find(|m| m.name == name) {
+                let message = format!("`{}` is also named `{}`", other.path.display(), name);
+                return Err(invalid(&path, message));
+            }
+            name
+        };
+
+        let mut src = String::new();
+        File::open(&path)?.read_to_string(&mut src)?;
+        let tokens = Lexer::new(&src)
+            .lex()
+            .map_err(|e| invalid(&path, e.to_string()))?;
+        let ast = Parser::new(tokens)
+            .parse()
+            .map_err(|e| invalid(&path, e.to_string()))?;
+        let module = Module { name, path, ast };
+
+        self.loading.push(canonical.clone());
+        for item in module.items() {
+            match item {
+                Expr::Import(import) => {
+                    let dir = module.path.parent().unwrap_or_else(|| Path::new(""));
+                    self.load(dir.join(import))?;
+                }
+                Expr::FuncDef(_) | Expr::Use(_) | Expr::Extern(_) => {}
+                _ if is_script => {}
+                _ => {
+                    let message = "an imported file can only declare functions".to_owned();
+                    return Err(invalid(&module.path, message));
+                }
+            }
+            if item.children().into_iter().any(has_import) {
+                let message = "`import` has to be at the top level of a file".to_owned();
+                return Err(invalid(&module.path, message));
+            }
+        }
+        self.loading.pop();
+
+        self.modules.push(module);
+        self.loaded.push(canonical);
+        Ok(())
+    }
+}
+
+fn has_import(ast: &Expr) -> bool {
+    matches!(ast, Expr::Import(_)) || ast.children().into_iter().any(has_import)
+}
+
+fn invalid(path: &Path, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_come_first() {
+        let modules = load(Path::new("tests/fixtures/imports/ok.ski")).unwrap();
+        let names: Vec<&str> = modules.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["util", "ok"]);
+    }
+
+    #[test]
+    fn files_can_leave_out_the_braces() {
+        let modules = load(Path::new("tests/fixtures/braceless/build.ski")).unwrap();
+        let functions: Vec<&str> = modules[0]
+            .items()
+            .iter()
+            .filter_map(|item| match item {
+                Expr::FuncDef(f) => Some(f.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(functions, ["copy_all", "clean"]);
+        assert_eq!(modules[1].items().len(), 5);
+    }
+
+    #[test]
+    fn import_cycles_are_errors() {
+        let err = load(Path::new("tests/fixtures/cycle/a.ski")).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("import cycle: "), "{}", message);
+        assert!(message.ends_with("a.ski"), "{}", message);
+    }
+
+    #[test]
+    fn imported_files_only_declare_functions() {
+        let err = load(Path::new("tests/fixtures/imports/main.ski")).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("an imported file can only declare functions"));
+    }
+
+    #[test]
+    fn module_names_are_identifiers() {
+        assert_eq!(name(Path::new("dir/café.ski")).unwrap(), "café");
+        assert!(name(Path::new("dir/my-tools.ski")).is_err());
+        assert!(name(Path::new("dir/1st.ski")).is_err());
+    }
+}
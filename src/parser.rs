@@ -2,7 +2,7 @@ use std::boxed::Box;
 
 use crate::ast::*;
 use crate::errors::ParseError;
-use crate::lexer::{Keyword, Literal, Pos, Symbol, Token, TokenKind};
+use crate::lexer::{Keyword, Literal, Pos, StrPart, Symbol, Token, TokenKind};
 
 type PResult = Result<Expr, ParseError>;
 
@@ -19,13 +19,13 @@ macro_rules! expect_keyword {
 
 macro_rules! expect_optional_keyword {
     ($self:ident, $keyword:ident) => {
-        $self.expect_optional_token(&TokenKind::Keyword(Keyword::$keyword));
+        $self.expect_optional_token(&TokenKind::Keyword(Keyword::$keyword))
     };
 }
 
 macro_rules! expect_optional_symbol {
     ($self:ident, $symbol:ident) => {
-        $self.expect_optional_token(&TokenKind::Symbol(Symbol::$symbol));
+        $self.expect_optional_token(&TokenKind::Symbol(Symbol::$symbol))
     };
 }
 
@@ -43,17 +43,28 @@ impl Parser {
         }
     }
 
+    /// Parses a whole file, which is either its statements or one block
+    /// holding them
     pub fn parse(&mut self) -> PResult {
-        self.eat_stmt()
+        let mut stmts = Vec::new();
+        while self.tokens.get(self.cursor).is_some_and(|t| t.token_kind != TokenKind::Eof) {
+            stmts.push(self.eat_stmt()?);
+        }
+        if stmts.len() == 1 {
+            Ok(stmts.remove(0))
+        } else {
+            Ok(Expr::Block(stmts))
+        }
     }
 
     fn eat_stmt(&mut self) -> PResult {
         let tok = self.eat_token();
-        if let &TokenKind::Keyword(ref keyw) = &tok.token_kind {
+        if let TokenKind::Keyword(keyw) = &tok.token_kind {
             match *keyw {
                 Keyword::Function => return self.eat_fn_decl(),
                 Keyword::Let => return self.eat_var_decl(),
                 Keyword::Const => return self.eat_const_decl(),
+                Keyword::Export => return self.eat_export(),
                 Keyword::For => return self.eat_for(),
                 Keyword::Num => unreachable!(),
                 Keyword::In => unreachable!(),
@@ -64,13 +75,36 @@ impl Parser {
                 Keyword::Else => unreachable!(),
                 Keyword::Continue => return self.eat_continue(),
                 Keyword::Break => return self.eat_break(),
+                Keyword::Match => return self.eat_match(),
+                Keyword::Flags => return self.eat_flags(),
+                Keyword::Try => return self.eat_try(),
+                Keyword::Import => return self.eat_import(),
+                Keyword::Use => return self.eat_use(),
+                Keyword::Extern => return self.eat_extern(),
+                Keyword::Spawn => {
+                    let spawn = self.eat_spawn()?;
+                    expect_optional_symbol!(self, SemiColon);
+                    return Ok(spawn);
+                }
+                Keyword::Catch | Keyword::Finally => {
+                    return Err(ParseError::Error("expected 'try' before this block", line!()))
+                }
             }
         } else if let &TokenKind::Symbol(Symbol::OpenBracket) = &tok.token_kind {
             return self.eat_block();
-        } else if let &TokenKind::Identifier(ref ident) = &tok.token_kind {
+        } else if let &TokenKind::Literal(Literal::Command(ref parts)) = &tok.token_kind {
+            let command = Parser::command(parts.clone())?;
+            expect_optional_symbol!(self, SemiColon);
+            return Ok(command);
+        } else if let TokenKind::Identifier(ident) = &tok.token_kind {
             let clone = ident.clone();
+            let clone = self.eat_path(clone)?;
             match self.peek_token()?.token_kind {
-                TokenKind::Symbol(Symbol::OpenParen) => return self.eat_fn_call(clone),
+                TokenKind::Symbol(Symbol::OpenParen) => {
+                    let call = self.eat_fn_call(clone)?;
+                    expect_optional_symbol!(self, SemiColon);
+                    return Ok(call);
+                }
                 TokenKind::Symbol(Symbol::AddAssign)
                 | TokenKind::Symbol(Symbol::SubAssign)
                 | TokenKind::Symbol(Symbol::MulAssign)
@@ -105,6 +139,7 @@ impl Parser {
     }
 
     fn eat_if(&mut self) -> PResult {
+        let pos = self.prev_pos();
         let cond = self.eat_expr()?;
         let then = self.eat_stmt()?;
         let else_: Expr = if expect_optional_keyword!(self, Else) {
@@ -112,10 +147,16 @@ impl Parser {
         } else {
             Expr::Block(Vec::new())
         };
-        Ok(Expr::If(Box::new(If { cond, then, else_ })))
+        Ok(Expr::If(Box::new(If {
+            cond,
+            then,
+            else_,
+            pos,
+        })))
     }
 
-    fn eat_assign(&mut self) -> Result<(String, Expr, bool), ParseError> {
+    fn eat_assign(&mut self) -> Result<(String, Expr, bool, SourcePos), ParseError> {
+        let pos = SourcePos(self.peek_token()?.pos());
         let name = self.eat_ident()?;
         let is_numeric = expect_optional_symbol!(self, Colon);
         if is_numeric {
@@ -124,28 +165,50 @@ impl Parser {
         expect_symbol!(self, Assign, "expected '='");
         let value = self.eat_expr()?;
         expect_symbol!(self, SemiColon, "expected ';'");
-        Ok((name, value, is_numeric))
+        Ok((name, value, is_numeric, pos))
     }
 
     fn eat_var_decl(&mut self) -> PResult {
-        let (name, value, is_numeric) = self.eat_assign()?;
+        let (name, value, is_numeric, pos) = self.eat_assign()?;
         Ok(Expr::VariableDecl(Box::new(VariableDecl {
             name,
             value,
             is_numeric,
+            is_exported: false,
+            pos,
         })))
     }
 
     fn eat_const_decl(&mut self) -> PResult {
-        let (name, value, is_numeric) = self.eat_assign()?;
+        let (name, value, is_numeric, pos) = self.eat_assign()?;
         Ok(Expr::VariableDecl(Box::new(VariableDecl {
             name,
             value,
             is_numeric,
+            is_exported: false,
+            pos,
         })))
     }
 
+    fn eat_export(&mut self) -> PResult {
+        let is_let = match self.eat_token().token_kind {
+            TokenKind::Keyword(Keyword::Let) => true,
+            TokenKind::Keyword(Keyword::Const) => false,
+            _ => return Err(ParseError::Error("expected 'let' or 'const'", line!())),
+        };
+        let mut decl = if is_let {
+            self.eat_var_decl()?
+        } else {
+            self.eat_const_decl()?
+        };
+        if let Expr::VariableDecl(ref mut v) = decl {
+            v.is_exported = true;
+        }
+        Ok(decl)
+    }
+
     fn eat_mut_assign(&mut self, name: String) -> PResult {
+        let pos = SourcePos(self.peek_token()?.pos());
         let op = match self.eat_token().token_kind {
             TokenKind::Symbol(Symbol::AddAssign) => BinaryOpKind::Add,
             TokenKind::Symbol(Symbol::SubAssign) => BinaryOpKind::Sub,
@@ -159,12 +222,25 @@ impl Parser {
             left: Expr::Variable(name),
             op,
             right,
+            pos,
         })))
     }
 
     fn eat_fn_decl(&mut self) -> PResult {
-        let mut params: Vec<String> = Vec::new();
+        let pos = SourcePos(self.peek_token()?.pos());
         let name = self.eat_ident()?;
+        let params = self.eat_params()?;
+        let body = self.eat_stmt()?;
+        Ok(Expr::FuncDef(Box::new(FuncDef {
+            name,
+            params,
+            body,
+            pos,
+        })))
+    }
+
+    fn eat_params(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut params: Vec<String> = Vec::new();
         expect_symbol!(self, OpenParen, "expected symbol '('");
         if let TokenKind::Identifier(_) = self.peek_token()?.token_kind {
             loop {
@@ -178,30 +254,77 @@ impl Parser {
         } else {
             expect_symbol!(self, CloseParen, "expected symbol ')'");
         }
-        let body = self.eat_stmt()?;
-        Ok(Expr::FuncDef(Box::new(FuncDef { name, params, body })))
+        Ok(params)
+    }
+
+    /// `extern fn name(params) from "file.bat" :label returns RESULT;`, where
+    /// the label and the result are optional
+    fn eat_extern(&mut self) -> PResult {
+        expect_keyword!(self, Function, "expected 'fn'");
+        let name = self.eat_ident()?;
+        let params = self.eat_params()?;
+        match self.eat_token().token_kind {
+            TokenKind::Identifier(ref t) if t == "from" => {}
+            _ => return Err(ParseError::Error("expected 'from'", line!())),
+        }
+        let path = match self.eat_token().token_kind {
+            TokenKind::Literal(Literal::Str(ref s)) => s.to_string(),
+            _ => return Err(ParseError::Error("expected the path of a batch file", line!())),
+        };
+        let label = if expect_optional_symbol!(self, Colon) {
+            Some(self.eat_ident()?)
+        } else {
+            None
+        };
+        let result = match self.peek_token()?.token_kind {
+            TokenKind::Identifier(ref t) if t == "returns" => {
+                self.eat_token();
+                Some(self.eat_ident()?)
+            }
+            _ => None,
+        };
+        expect_symbol!(self, SemiColon, "expected ';'");
+        Ok(Expr::Extern(Box::new(Extern {
+            name,
+            params,
+            path,
+            label,
+            result,
+        })))
     }
 
     fn eat_fn_call(&mut self, func_name: String) -> PResult {
         expect_symbol!(self, OpenParen, "expected '('");
-        let mut params: Vec<Expr> = Vec::new();
+        let params = self.eat_list(Symbol::CloseParen, "expected ',' or ')'")?;
+        Ok(Expr::FuncCall(Box::new(FuncCall { func_name, params })))
+    }
+
+    fn eat_array(&mut self) -> PResult {
+        let items = self.eat_list(Symbol::CloseSquareBracket, "expected ',' or ']'")?;
+        Ok(Expr::Array(items))
+    }
+
+    /// Comma separated expressions up to and including `close`
+    fn eat_list(&mut self, close: Symbol, err: &'static str) -> Result<Vec<Expr>, ParseError> {
+        let close = TokenKind::Symbol(close);
+        let mut items: Vec<Expr> = Vec::new();
+        if self.expect_optional_token(&close) {
+            return Ok(items);
+        }
         loop {
-            let tok = self.eat_expr()?;
-            params.push(tok);
-            match self.eat_token().token_kind {
-                TokenKind::Symbol(Symbol::Comma) => continue,
-                TokenKind::Symbol(Symbol::CloseParen) => break,
-                _ => return Err(ParseError::Error("expected ',' or ')'", line!())),
+            items.push(self.eat_expr()?);
+            if self.expect_optional_token(&close) {
+                return Ok(items);
             }
+            self.expect_token(&TokenKind::Symbol(Symbol::Comma), err)?;
         }
-        expect_optional_symbol!(self, SemiColon);
-        Ok(Expr::FuncCall(Box::new(FuncCall { func_name, params })))
     }
 
     fn eat_while(&mut self) -> PResult {
+        let pos = self.prev_pos();
         let cond = self.eat_expr()?;
         let body = self.eat_stmt()?;
-        Ok(Expr::While(Box::new(While { cond, body })))
+        Ok(Expr::While(Box::new(While { cond, body, pos })))
     }
 
     fn eat_loop(&mut self) -> PResult {
@@ -226,17 +349,173 @@ impl Parser {
     }
 
     fn eat_for(&mut self) -> PResult {
+        let pos = SourcePos(self.peek_token()?.pos());
         let item = self.eat_ident()?;
         expect_keyword!(self, In, "expected keyword 'in'");
-        let container = self.eat_stmt()?;
+        let container = self.eat_expr()?;
         let body = self.eat_stmt()?;
         Ok(Expr::For(Box::new(For {
             item,
             container,
             body,
+            pos,
+        })))
+    }
+
+    fn eat_match(&mut self) -> PResult {
+        let pos = self.prev_pos();
+        // `match /i value` compares case-insensitively, like cmd's `IF /I`
+        let ignore_case = expect_optional_symbol!(self, Div);
+        if ignore_case && self.eat_ident()? != "i" {
+            return Err(ParseError::Error("expected '/i'", line!()));
+        }
+        let value = self.eat_expr()?;
+        expect_symbol!(self, OpenBracket, "expected '{'");
+        let mut arms: Vec<MatchArm> = Vec::new();
+        let mut default: Option<Expr> = None;
+        while !expect_optional_symbol!(self, CloseBracket) {
+            if default.is_some() {
+                return Err(ParseError::Error("'_' must be the last arm", line!()));
+            }
+            let is_default = self.peek_token()?.token_kind == TokenKind::Identifier("_".to_owned());
+            let mut patterns: Vec<Expr> = Vec::new();
+            if is_default {
+                self.eat_token();
+            } else {
+                patterns.push(self.eat_literal()?);
+                while expect_optional_symbol!(self, BinaryOr) {
+                    patterns.push(self.eat_literal()?);
+                }
+            }
+            expect_symbol!(self, FatArrow, "expected '=>'");
+            let body = self.eat_stmt()?;
+            expect_optional_symbol!(self, Comma);
+            if is_default {
+                default = Some(body);
+            } else {
+                arms.push(MatchArm { patterns, body });
+            }
+        }
+        Ok(Expr::Match(Box::new(Match {
+            value,
+            arms,
+            default,
+            ignore_case,
+            pos,
+        })))
+    }
+
+    /// `try { ... } catch err { ... } finally { ... }`, with at least one of
+    /// `catch` and `finally`
+    fn eat_try(&mut self) -> PResult {
+        let pos = self.prev_pos();
+        let body = self.eat_stmt()?;
+        let catch = if expect_optional_keyword!(self, Catch) {
+            let name = self.eat_ident()?;
+            Some((name, self.eat_stmt()?))
+        } else {
+            None
+        };
+        let finally = if expect_optional_keyword!(self, Finally) {
+            Some(self.eat_stmt()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            return Err(ParseError::Error("expected 'catch' or 'finally'", line!()));
+        }
+        Ok(Expr::Try(Box::new(Try {
+            body,
+            catch,
+            finally,
+            pos,
         })))
     }
 
+    /// `import "util.ski";`
+    fn eat_import(&mut self) -> PResult {
+        let path = match self.eat_token().token_kind {
+            TokenKind::Literal(Literal::Str(ref s)) => s.to_string(),
+            _ => return Err(ParseError::Error("expected the path of a ski file", line!())),
+        };
+        expect_symbol!(self, SemiColon, "expected ';'");
+        Ok(Expr::Import(path))
+    }
+
+    /// `use util::copy_all;` or `use util::{copy_all, move_all};`
+    fn eat_use(&mut self) -> PResult {
+        let module = self.eat_ident()?;
+        expect_symbol!(self, PathSep, "expected '::'");
+        let mut names: Vec<String> = Vec::new();
+        if expect_optional_symbol!(self, OpenBracket) {
+            loop {
+                names.push(self.eat_ident()?);
+                match self.eat_token().token_kind {
+                    TokenKind::Symbol(Symbol::Comma) => continue,
+                    TokenKind::Symbol(Symbol::CloseBracket) => break,
+                    _ => return Err(ParseError::Error("expected ',' or '}'", line!())),
+                };
+            }
+        } else {
+            names.push(self.eat_ident()?);
+        }
+        expect_symbol!(self, SemiColon, "expected ';'");
+        Ok(Expr::Use(Box::new(Use { module, names })))
+    }
+
+    /// `spawn { ... }` or `spawn f(x)`
+    fn eat_spawn(&mut self) -> PResult {
+        let body = match self.peek_token()?.token_kind {
+            TokenKind::Symbol(Symbol::OpenBracket) => self.eat_stmt()?,
+            _ => {
+                let name = self.eat_ident()?;
+                let name = self.eat_path(name)?;
+                self.eat_fn_call(name)?
+            }
+        };
+        Ok(Expr::Spawn(Box::new(body)))
+    }
+
+    /// `flags { verbose: bool, out: path = "." }`
+    fn eat_flags(&mut self) -> PResult {
+        expect_symbol!(self, OpenBracket, "expected '{'");
+        let mut flags: Vec<Flag> = Vec::new();
+        while !expect_optional_symbol!(self, CloseBracket) {
+            let pos = SourcePos(self.peek_token()?.pos());
+            let name = self.eat_ident()?;
+            expect_symbol!(self, Colon, "expected ':'");
+            let kind = match self.eat_token().token_kind {
+                TokenKind::Keyword(Keyword::Num) => FlagKind::Num,
+                TokenKind::Identifier(ref t) if t == "bool" => FlagKind::Bool,
+                TokenKind::Identifier(ref t) if t == "str" => FlagKind::Str,
+                TokenKind::Identifier(ref t) if t == "path" => FlagKind::Path,
+                _ => {
+                    return Err(ParseError::Error(
+                        "expected a flag type: bool, str, path or num",
+                        line!(),
+                    ))
+                }
+            };
+            let default = if expect_optional_symbol!(self, Assign) {
+                Some(self.eat_literal()?)
+            } else {
+                None
+            };
+            flags.push(Flag {
+                name,
+                kind,
+                default,
+                pos,
+            });
+            if !expect_optional_symbol!(self, Comma) {
+                expect_symbol!(self, CloseBracket, "expected ',' or '}'");
+                break;
+            }
+        }
+        expect_optional_symbol!(self, SemiColon);
+        Ok(Expr::Flags(flags))
+    }
+
     fn eat_expr(&mut self) -> PResult {
         let has_open_paren = expect_optional_symbol!(self, OpenParen);
         match self.peek_token()?.token_kind {
@@ -260,11 +539,11 @@ impl Parser {
                         return Ok($left)
                     },
                     TokenKind::Eof => return Err(ParseError::Eof),
-                    TokenKind::Literal(_) => return Ok(self.eat_literal()?),
+                    TokenKind::Literal(_) => return self.eat_literal(),
                     _ => return Ok($left)
                 }
             }
-        };
+        }
 
         let left = self.eat_var_or_literal()?;
         let op = bin_op!(
@@ -290,9 +569,9 @@ impl Parser {
             BinaryAnd,
             BinaryOr
         );
-        self.eat_token();
+        let pos = SourcePos(self.eat_token().pos());
         let right = self.eat_expr()?;
-        let b = Expr::Binary(Box::new(BinaryExpr { left, op, right }));
+        let b = Expr::Binary(Box::new(BinaryExpr { left, op, right, pos }));
         Ok(if has_open_paren {
           expect_symbol!(self, CloseParen, "expected ')'");
           Expr::Paren(Box::new(b))
@@ -323,7 +602,8 @@ impl Parser {
                             Expr::Binary(
                                 Box::new(
                                     BinaryExpr {
-                                        left: Expr::Unary(Box::new(UnaryExpr { op, child })),
+                                        left: Parser::unary(op, child),
+                                        pos: SourcePos($self.peek_token()?.pos()),
                                         op: BinaryOpKind::from_token(&self.eat_token().token_kind)?,
                                         right: self.eat_expr()?,
                                     }
@@ -334,23 +614,212 @@ impl Parser {
                     _ => {}
                 }
             }
-        };
+        }
 
         is_op_next!(
             self, Add, Sub, Mul, Div, Assign, Eq, Ne, Gt, Lt, GtEq, LtEq, Shr, Shl, Xor,
             LogicalAnd, LogicalOr, BinaryAnd, BinaryOr
         );
-        Ok(Expr::Unary(Box::new(UnaryExpr { op, child })))
+        Ok(Parser::unary(op, child))
+    }
+
+    /// Folds a minus sign into an integer literal, so `-5` is the literal `-5`
+    fn unary(op: UnaryOpKind, child: Expr) -> Expr {
+        match (op, child) {
+            (UnaryOpKind::Minus, Expr::Int(i)) => Expr::Int(-i),
+            (op, child) => Expr::Unary(Box::new(UnaryExpr { op, child })),
+        }
+    }
+
+    fn int(i: u64) -> PResult {
+        if i > i64::MAX as u64 {
+            return Err(ParseError::Error("Integer literal is too large", line!()));
+        }
+        Ok(Expr::Int(i as i64))
     }
 
     fn eat_literal(&mut self) -> PResult {
         match self.eat_token().token_kind {
             TokenKind::Literal(Literal::Str(ref s)) => Ok(Expr::Str(s.to_string())),
-            TokenKind::Literal(Literal::Int(i)) => Ok(Expr::Int(i)),
+            TokenKind::Literal(Literal::Int(i)) => Parser::int(i),
+            TokenKind::Literal(Literal::Bool(b)) => Ok(Expr::Bool(b)),
+            TokenKind::Literal(Literal::Interpolated(ref parts)) => {
+                Parser::interpolated(parts.clone())
+            }
             _ => Err(ParseError::Error("expected literal", line!())),
         }
     }
 
+    /// Parses the `{expr}` parts of an interpolated string, each of which was
+    /// lexed on its own
+    fn interpolated(parts: Vec<StrPart>) -> PResult {
+        let mut exprs: Vec<Expr> = Vec::with_capacity(parts.len());
+        for part in parts {
+            exprs.push(match part {
+                StrPart::Text(text) => Expr::Str(text),
+                StrPart::Code(mut tokens) => {
+                    // The closing brace stops `eat_expr` from running off the end
+                    tokens.push(Token::new(TokenKind::Symbol(Symbol::CloseBracket), Pos::new()));
+                    let mut parser = Parser::new(tokens);
+                    let expr = parser.eat_expr()?;
+                    expect_symbol!(parser, CloseBracket, "expected '}'");
+                    if parser.cursor < parser.tokens.len() {
+                        return Err(ParseError::Error("expected '}'", line!()));
+                    }
+                    expr
+                }
+            });
+        }
+        Ok(Expr::Interpolated(exprs))
+    }
+
+    /// Splits a backtick command into words and the pipes and redirections
+    /// between them. Double quotes group words, and everything inside them
+    /// is part of a word
+    fn command(parts: Vec<StrPart>) -> PResult {
+        let mut stages: Vec<Stage> = Vec::new();
+        let mut stage = Stage {
+            words: Vec::new(),
+            redirects: Vec::new(),
+        };
+        // `None` between words. `""` starts a word without adding text
+        let mut word: Option<Vec<StrPart>> = None;
+        let mut quoted = false;
+        // a redirection still waiting for its file
+        let mut redirect: Option<RedirectKind> = None;
+
+        macro_rules! end_word {
+            () => {
+                if let Some(parts) = word.take() {
+                    let expr = Parser::word(parts)?;
+                    match redirect.take() {
+                        Some(kind) => stage.redirects.push(Redirect {
+                            kind,
+                            target: Some(expr),
+                        }),
+                        None => stage.words.push(expr),
+                    }
+                }
+            };
+        }
+        macro_rules! end_stage {
+            () => {
+                end_word!();
+                if redirect.is_some() {
+                    return Err(ParseError::Error("expected a file to redirect", line!()));
+                }
+                if stage.words.is_empty() {
+                    return Err(ParseError::Error("expected a command", line!()));
+                }
+            };
+        }
+
+        for part in parts {
+            let text = match part {
+                StrPart::Text(text) => text,
+                code => {
+                    word.get_or_insert_with(Vec::new).push(code);
+                    continue;
+                }
+            };
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                let kind = match c {
+                    '"' => {
+                        quoted = !quoted;
+                        word.get_or_insert_with(Vec::new);
+                        continue;
+                    }
+                    _ if quoted => {
+                        Parser::push_text(word.get_or_insert_with(Vec::new), c);
+                        continue;
+                    }
+                    _ if c.is_whitespace() => {
+                        end_word!();
+                        continue;
+                    }
+                    '|' => {
+                        end_stage!();
+                        stages.push(std::mem::replace(
+                            &mut stage,
+                            Stage {
+                                words: Vec::new(),
+                                redirects: Vec::new(),
+                            },
+                        ));
+                        continue;
+                    }
+                    '&' => {
+                        return Err(ParseError::Error(
+                            "'&' can only appear in '2>&1' or inside quotes",
+                            line!(),
+                        ))
+                    }
+                    '<' => RedirectKind::In,
+                    '>' if chars.peek() == Some(&'>') => {
+                        chars.next();
+                        RedirectKind::Append
+                    }
+                    '>' => RedirectKind::Out,
+                    // `2>`, `2>>` or `2>&1`, but not a `2` in a word
+                    '2' if word.is_none() && chars.peek() == Some(&'>') => {
+                        chars.next();
+                        match chars.peek() {
+                            Some('>') => {
+                                chars.next();
+                                RedirectKind::ErrAppend
+                            }
+                            Some('&') => {
+                                chars.next();
+                                if chars.next() != Some('1') {
+                                    return Err(ParseError::Error("expected '2>&1'", line!()));
+                                }
+                                RedirectKind::ErrToOut
+                            }
+                            _ => RedirectKind::Err,
+                        }
+                    }
+                    _ => {
+                        Parser::push_text(word.get_or_insert_with(Vec::new), c);
+                        continue;
+                    }
+                };
+                end_word!();
+                if redirect.is_some() {
+                    return Err(ParseError::Error("expected a file to redirect", line!()));
+                }
+                if kind == RedirectKind::ErrToOut {
+                    stage.redirects.push(Redirect { kind, target: None });
+                } else {
+                    redirect = Some(kind);
+                }
+            }
+        }
+        if quoted {
+            return Err(ParseError::Error("unterminated quote in command", line!()));
+        }
+        end_stage!();
+        stages.push(stage);
+        Ok(Expr::Command(Box::new(Command { stages })))
+    }
+
+    fn push_text(word: &mut Vec<StrPart>, c: char) {
+        match word.last_mut() {
+            Some(StrPart::Text(text)) => text.push(c),
+            _ => word.push(StrPart::Text(c.to_string())),
+        }
+    }
+
+    /// One word of a command, which is a plain string unless it has `{expr}`
+    /// parts
+    fn word(parts: Vec<StrPart>) -> PResult {
+        match parts.as_slice() {
+            [] => Ok(Expr::Str(String::new())),
+            [StrPart::Text(text)] => Ok(Expr::Str(text.clone())),
+            _ => Parser::interpolated(parts),
+        }
+    }
+
     fn eat_ident(&mut self) -> Result<String, ParseError> {
         match self.eat_token().token_kind {
             TokenKind::Identifier(ref ident) => Ok(ident.to_string()),
@@ -358,15 +827,42 @@ impl Parser {
         }
     }
 
+    /// The rest of `module::name` after `module`, which can only name a
+    /// function
+    fn eat_path(&mut self, ident: String) -> Result<String, ParseError> {
+        if !expect_optional_symbol!(self, PathSep) {
+            return Ok(ident);
+        }
+        let name = self.eat_ident()?;
+        if self.peek_token()?.token_kind != TokenKind::Symbol(Symbol::OpenParen) {
+            return Err(ParseError::Error("expected '(' after a module's function", line!()));
+        }
+        Ok(format!("{}::{}", ident, name))
+    }
+
     fn eat_var_or_literal(&mut self) -> PResult {
        // dbg!(&self.eat_token().token_kind);
         match self.eat_token().token_kind {
-            TokenKind::Identifier(ref ident) => Ok(Expr::Variable(ident.to_string())),
+            TokenKind::Identifier(ref ident) => {
+                let ident = ident.to_string();
+                let ident = self.eat_path(ident)?;
+                match self.peek_token()?.token_kind {
+                    TokenKind::Symbol(Symbol::OpenParen) => self.eat_fn_call(ident),
+                    _ => Ok(Expr::Variable(ident)),
+                }
+            }
             TokenKind::Literal(Literal::Str(ref s)) => Ok(Expr::Str(s.to_string())),
-            TokenKind::Literal(Literal::Int(i)) => Ok(Expr::Int(i)),
+            TokenKind::Literal(Literal::Int(i)) => Parser::int(i),
+            TokenKind::Literal(Literal::Bool(b)) => Ok(Expr::Bool(b)),
+            TokenKind::Literal(Literal::Interpolated(ref parts)) => {
+                Parser::interpolated(parts.clone())
+            }
+            TokenKind::Literal(Literal::Command(ref parts)) => Parser::command(parts.clone()),
+            TokenKind::Keyword(Keyword::Spawn) => self.eat_spawn(),
             TokenKind::Symbol(Symbol::OpenParen) => {
                 self.eat_var_or_literal()
             },
+            TokenKind::Symbol(Symbol::OpenSquareBracket) => self.eat_array(),
             _ => Err(ParseError::Error("expected identifier or literal", line!())),
         }
     }
@@ -394,17 +890,17 @@ impl Parser {
         &self.tokens[self.cursor - 1]
     }
 
+    /// The position of the token just eaten, like the keyword that starts
+    /// a statement
+    fn prev_pos(&self) -> SourcePos {
+        SourcePos(self.tokens[self.cursor - 1].pos())
+    }
+
     fn peek_token(&mut self) -> Result<&Token, ParseError> {
-        if &self.tokens.len() <= &(self.cursor) {
+        if self.tokens.len() <= self.cursor {
             Err(ParseError::Eof)
         } else {
             Ok(&self.tokens[self.cursor])
         }
     }
-
-    fn ident_val(t: TokenKind) -> String {
-        match t {
-            _ => unreachable!(),
-        }
-    }
 }